@@ -1,6 +1,6 @@
-use std::ffi::c_float;
+use macroquad::prelude::*;
 
-use macroquad::{miniquad::gl::WGL_CONTEXT_RESET_NOTIFICATION_STRATEGY_ARB, prelude::*};
+use crate::config::Section;
 
 pub fn get_camera_forward(camera: &Camera3D) -> Vec3 {
     (camera.target-camera.position).normalize()
@@ -17,7 +17,7 @@ pub fn get_camera_right(camera: &Camera3D) -> Vec3 {
 
 pub fn camera_move_forward(camera: &mut Camera3D, distance: f32, move_in_world_plane: bool) {
     let mut forward = get_camera_forward(camera);
-    if (move_in_world_plane) {
+    if move_in_world_plane {
         forward.y = 0.;
         forward = forward.normalize();
     }
@@ -34,7 +34,7 @@ pub fn camera_move_up(camera: &mut Camera3D, distance: f32) {
 
 pub fn camera_move_right(camera: &mut Camera3D, distance: f32, move_in_world_plane: bool) {
     let mut right = get_camera_right(camera);
-    if (move_in_world_plane) {
+    if move_in_world_plane {
         right.y = 0.;
         right = right.normalize();
     }
@@ -50,10 +50,20 @@ pub fn camera_move_to_target(camera: &mut Camera3D, delta: f32) {
     camera.position = camera.target -distance*get_camera_forward(camera);
 }
 
+/// Keeps the camera at least `clearance` above `ground(x, z)`, moving the target along with it.
+pub fn camera_clamp_to_ground(camera: &mut Camera3D, clearance: f32, ground: impl Fn(f32, f32) -> f32) {
+    let floor = ground(camera.position.x, camera.position.z) + clearance;
+    if camera.position.y < floor {
+        let lift = floor - camera.position.y;
+        camera.position.y += lift;
+        camera.target.y += lift;
+    }
+}
+
 pub fn rotate_vector_axis_angle(input: Vec3, axis: Vec3, angle: f32) -> Vec3 {
     let rot = Quat::from_axis_angle(axis, angle);
     let mat = Mat4::from_rotation_translation(rot, vec3(0.0, 0.0, 0.0));
-    return mat.transform_vector3(input);
+    mat.transform_vector3(input)
 }
 
 pub fn camera_yaw(camera: &mut Camera3D, angle: f32, rotate_around_target: bool) {
@@ -83,7 +93,6 @@ pub fn camera_pitch(camera: &mut Camera3D, angle: f32, rotate_around_target: boo
     }
     view_vector = rotate_vector_axis_angle(view_vector, get_camera_right(camera), angle);
     if rotate_around_target {
-        assert!(false);
         camera.position = camera.target-view_vector;
     }
     else {
@@ -94,91 +103,516 @@ pub fn camera_pitch(camera: &mut Camera3D, angle: f32, rotate_around_target: boo
     }
 }
 
+/// The six clip planes of a camera, each stored as `(normal, distance)` with the normal pointing inwards.
+#[derive(Clone, Copy, Debug)]
+pub struct Frustum {
+    planes: [Vec4; 6],
+}
+
+impl Frustum {
+    pub fn from_camera(camera: &Camera3D) -> Frustum {
+        let matrix = camera.matrix();
+        let (x, y, z, w) = (matrix.row(0), matrix.row(1), matrix.row(2), matrix.row(3));
+        let planes = [w + x, w - x, w + y, w - y, w + z, w - z]
+            .map(|plane| plane / plane.xyz().length());
+        Frustum { planes }
+    }
+
+    /// Returns false only if the box lies completely outside one of the planes.
+    pub fn intersects_aabb(&self, min: Vec3, max: Vec3) -> bool {
+        self.planes.iter().all(|plane| {
+            let normal = plane.xyz();
+            let furthest = Vec3::select(normal.cmpge(Vec3::ZERO), max, min);
+            normal.dot(furthest) + plane.w >= 0.0
+        })
+    }
+}
+
+/// Unprojects a point in normalized device coordinates into a world space ray `(origin, direction)`.
+pub fn ray_from_ndc(view_projection: Mat4, ndc: Vec2) -> (Vec3, Vec3) {
+    let inverse = view_projection.inverse();
+    let near = inverse.project_point3(ndc.extend(-1.0));
+    let far = inverse.project_point3(ndc.extend(1.0));
+    (near, (far - near).normalize())
+}
+
+/// World space ray through the mouse cursor.
+pub fn mouse_ray(camera: &Camera3D) -> (Vec3, Vec3) {
+    let (x, y) = mouse_position();
+    let ndc = Vec2::new(2.0 * x / screen_width() - 1.0, 1.0 - 2.0 * y / screen_height());
+    ray_from_ndc(camera.matrix(), ndc)
+}
+
 pub fn camera_roll(camera: &mut Camera3D, angle: f32) {
     camera.up = rotate_vector_axis_angle(camera.up, get_camera_forward(camera), angle);
 }
 
-pub fn update_camera(camera: &mut Camera3D) {
-    let lock_view = true;
-    let rotate_around_target = false;
-    let rotate_up = true;
-    let move_in_world_plane = false;
-    let dt = get_frame_time();
-    let camera_move_speed = dt;
-    let camera_rotation_speed = dt;
-    let camera_pan_speed = dt;
-    
-    if is_key_down(KeyCode::Down) {
-        camera_pitch(camera, -camera_rotation_speed, rotate_around_target, lock_view, rotate_up);
-    }
-    if is_key_down(KeyCode::Up) {
-        camera_pitch(camera, camera_rotation_speed, rotate_around_target, lock_view, rotate_up);
+/// Which of the update functions drives the camera.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CameraMode {
+    Free,
+    Walk,
+    Orbit,
+}
+
+/// State of the ground following walk mode.
+pub struct Walker {
+    /// Height of the camera above the ground.
+    pub eye_height: f32,
+    pub walk_speed: f32,
+    pub gravity: f32,
+    pub jump_speed: f32,
+    /// Steepest slope in radians that can be walked up.
+    pub max_slope: f32,
+    /// Highest drop that is stepped down instead of fallen, on top of what walking down a slope
+    /// of `max_slope` drops.
+    pub step_height: f32,
+    pub vertical_velocity: f32,
+    pub grounded: bool,
+}
+
+impl Default for Walker {
+    fn default() -> Self {
+        Walker {
+            eye_height: 0.05,
+            walk_speed: 0.3,
+            gravity: 2.0,
+            jump_speed: 0.8,
+            max_slope: 50f32.to_radians(),
+            step_height: 0.01,
+            vertical_velocity: 0.0,
+            grounded: false,
+        }
     }
-    if is_key_down(KeyCode::Right) {
-        camera_yaw(camera, -camera_rotation_speed, rotate_around_target);
+}
+
+/// Applies slope limits, jumping and gravity after the camera moved horizontally from `start`.
+pub fn walk_step(camera: &mut Camera3D, walker: &mut Walker, start: Vec3, jump: bool, dt: f32, ground: impl Fn(f32, f32) -> f32) {
+    let run = (camera.position - start).xz().length();
+    if walker.grounded && run > 0.0 {
+        let rise = ground(camera.position.x, camera.position.z) - ground(start.x, start.z);
+        if rise / run > walker.max_slope.tan() {
+            // too steep, undo the horizontal move
+            let back = vec3(start.x - camera.position.x, 0.0, start.z - camera.position.z);
+            camera.position += back;
+            camera.target += back;
+        }
     }
-    if is_key_down(KeyCode::Left) {
-        camera_yaw(camera, camera_rotation_speed, rotate_around_target);
+
+    if jump && walker.grounded {
+        walker.vertical_velocity = walker.jump_speed;
+        walker.grounded = false;
     }
-    if is_key_down(KeyCode::Q) {
-        camera_roll(camera, -camera_rotation_speed);
+    walker.vertical_velocity -= walker.gravity*dt;
+    let mut lift = walker.vertical_velocity*dt;
+    let floor = ground(camera.position.x, camera.position.z) + walker.eye_height;
+    let max_drop = walker.step_height + (camera.position - start).xz().length()*walker.max_slope.tan();
+    if camera.position.y + lift <= floor || (walker.grounded && camera.position.y - floor <= max_drop) {
+        // land, or stick to the ground when walking downhill or down a small step
+        lift = floor - camera.position.y;
+        walker.vertical_velocity = 0.0;
+        walker.grounded = true;
+    } else {
+        // walked off a ledge
+        walker.grounded = false;
     }
-    if is_key_down(KeyCode::E) {
-        camera_roll(camera, camera_rotation_speed);
+    camera.position.y += lift;
+    camera.target.y += lift;
+}
+
+/// Settings of the orbit camera, which circles around `camera.target`.
+pub struct Orbit {
+    /// Lowest and highest elevation of the camera above the pivot in radians.
+    pub min_pitch: f32,
+    pub max_pitch: f32,
+    pub pan_speed: f32,
+    pub zoom_speed: f32,
+}
+
+impl Default for Orbit {
+    fn default() -> Self {
+        Orbit { min_pitch: 5f32.to_radians(), max_pitch: 85f32.to_radians(), pan_speed: 0.5, zoom_speed: 0.2 }
     }
+}
 
-    // Mouse pan
-    if is_mouse_button_down(MouseButton::Left) {
-        let mouse_delta: Vec2 = mouse_delta_position()/screen_width()*700.;
-        if mouse_delta.x > 0.0 {
-            camera_move_right(camera, camera_pan_speed, move_in_world_plane);
-        }
-        if mouse_delta.x < 0.0 {
-            camera_move_right(camera, -camera_pan_speed, move_in_world_plane);
-        }
-        if mouse_delta.y > 0.0 {
-            camera_move_up(camera, -camera_pan_speed);
-        }
-        if mouse_delta.y < 0.0 {
-            camera_move_up(camera, camera_pan_speed);
+/// Elevation of the camera above its target in radians.
+pub fn camera_elevation(camera: &Camera3D) -> f32 {
+    (camera.position-camera.target).normalize().y.clamp(-1.0, 1.0).asin()
+}
+
+/// Moves the pivot of an orbit camera to `pivot` while keeping the view direction and distance.
+pub fn camera_set_pivot(camera: &mut Camera3D, pivot: Vec3) {
+    let shift = pivot-camera.target;
+    camera.position += shift;
+    camera.target += shift;
+}
+
+/// Keys driving the camera. Walk mode uses `up` to jump.
+#[derive(Clone, Debug)]
+pub struct InputMap {
+    pub forward: KeyCode,
+    pub back: KeyCode,
+    pub left: KeyCode,
+    pub right: KeyCode,
+    pub up: KeyCode,
+    pub down: KeyCode,
+    pub pitch_up: KeyCode,
+    pub pitch_down: KeyCode,
+    pub yaw_left: KeyCode,
+    pub yaw_right: KeyCode,
+    pub roll_left: KeyCode,
+    pub roll_right: KeyCode,
+    pub zoom_in: KeyCode,
+    pub zoom_out: KeyCode,
+    pub sprint: KeyCode,
+}
+
+impl Default for InputMap {
+    fn default() -> Self {
+        InputMap {
+            forward: KeyCode::W,
+            back: KeyCode::S,
+            left: KeyCode::A,
+            right: KeyCode::D,
+            up: KeyCode::Space,
+            down: KeyCode::LeftControl,
+            pitch_up: KeyCode::Up,
+            pitch_down: KeyCode::Down,
+            yaw_left: KeyCode::Left,
+            yaw_right: KeyCode::Right,
+            roll_left: KeyCode::Q,
+            roll_right: KeyCode::E,
+            zoom_in: KeyCode::KpAdd,
+            zoom_out: KeyCode::KpSubtract,
+            sprint: KeyCode::LeftAlt,
         }
     }
-    else {
-        let mouse_delta: Vec2 = mouse_delta_position()/screen_width()*700.;
-        camera_yaw(camera, -mouse_delta.x, rotate_around_target);
-        camera_pitch(camera, -mouse_delta.y, rotate_around_target, lock_view, rotate_up);
+}
+
+/// Turns keyboard and mouse input into camera movement for the current `mode`.
+pub struct CameraController {
+    pub mode: CameraMode,
+    pub lock_view: bool,
+    pub rotate_up: bool,
+    pub move_in_world_plane: bool,
+    /// Units per second.
+    pub move_speed: f32,
+    /// Rate per second at which the velocity approaches `move_speed` while a movement key is held.
+    pub acceleration: f32,
+    /// Rate per second at which the velocity decays once all movement keys are released.
+    pub damping: f32,
+    /// Radians per second.
+    pub rotate_speed: f32,
+    /// Units moved for dragging the mouse across the whole window with the left button held.
+    pub pan_speed: f32,
+    pub sprint_multiplier: f32,
+    /// Radians of rotation for a mouse movement across the whole window.
+    pub mouse_sensitivity: f32,
+    /// Time constant of the mouse look smoothing in seconds, 0 turns smoothing off.
+    pub look_smoothing: f32,
+    /// Keep the free flying camera at least `ground_clearance` above the terrain.
+    pub collide: bool,
+    pub ground_clearance: f32,
+    pub input: InputMap,
+    pub walker: Walker,
+    pub orbit: Orbit,
+    /// Free flight velocity along the camera's right, up and forward axes.
+    velocity: Vec3,
+    /// Mouse look that smoothing has not applied yet.
+    pending_look: Vec2,
+}
+
+/// One frame of input to the free flying camera. Sampled from the keyboard and mouse by
+/// `CameraController::sample_input`, but can be built by hand to replay input deterministically.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CameraInput {
+    pub dt: f32,
+    /// Movement keys along the camera's right, up and forward axes, each in `-1..=1`.
+    pub movement: Vec3,
+    /// Rotation keys for yaw (left is positive), pitch (up is positive) and roll, each in `-1..=1`.
+    pub rotation: Vec3,
+    /// Mouse movement since the last frame in window units, where the window spans `-1..=1`.
+    pub mouse_delta: Vec2,
+    /// Whether the mouse pans instead of looking around.
+    pub pan: bool,
+    pub sprint: bool,
+    /// Change of the distance to the target.
+    pub zoom: f32,
+}
+
+impl Default for CameraController {
+    fn default() -> Self {
+        CameraController {
+            mode: CameraMode::Free,
+            lock_view: true,
+            rotate_up: true,
+            move_in_world_plane: false,
+            move_speed: 1.0,
+            acceleration: 8.0,
+            damping: 6.0,
+            rotate_speed: 1.0,
+            pan_speed: 1.0,
+            sprint_multiplier: 4.0,
+            mouse_sensitivity: 0.75,
+            look_smoothing: 0.0,
+            // off for the debug fly-through
+            collide: false,
+            ground_clearance: 0.03,
+            input: InputMap::default(),
+            walker: Walker::default(),
+            orbit: Orbit::default(),
+            velocity: Vec3::ZERO,
+            pending_look: Vec2::ZERO,
+        }
     }
+}
+
+impl CameraController {
+    /// Builds a controller from the `[camera]` section of the config file, keeping defaults for
+    /// missing keys.
+    pub fn from_config(config: &Section) -> Result<CameraController, String> {
+        let mut controller = CameraController::default();
+        let camera = config.section("camera")?;
+        camera.bool("lock_view", &mut controller.lock_view)?;
+        camera.bool("rotate_up", &mut controller.rotate_up)?;
+        camera.bool("move_in_world_plane", &mut controller.move_in_world_plane)?;
+        camera.f32("move_speed", &mut controller.move_speed)?;
+        camera.f32("acceleration", &mut controller.acceleration)?;
+        camera.f32("damping", &mut controller.damping)?;
+        camera.f32("rotate_speed", &mut controller.rotate_speed)?;
+        camera.f32("pan_speed", &mut controller.pan_speed)?;
+        camera.f32("sprint_multiplier", &mut controller.sprint_multiplier)?;
+        camera.f32("mouse_sensitivity", &mut controller.mouse_sensitivity)?;
+        camera.f32("look_smoothing", &mut controller.look_smoothing)?;
+        camera.bool("collide", &mut controller.collide)?;
+        camera.f32("ground_clearance", &mut controller.ground_clearance)?;
+
+        let walk = camera.section("walk")?;
+        walk.f32("eye_height", &mut controller.walker.eye_height)?;
+        walk.f32("walk_speed", &mut controller.walker.walk_speed)?;
+        walk.f32("gravity", &mut controller.walker.gravity)?;
+        walk.f32("jump_speed", &mut controller.walker.jump_speed)?;
+        let mut max_slope = controller.walker.max_slope.to_degrees();
+        walk.f32("max_slope_degrees", &mut max_slope)?;
+        controller.walker.max_slope = max_slope.to_radians();
+        walk.f32("step_height", &mut controller.walker.step_height)?;
 
-    // WASD movement
-    if is_key_down(KeyCode::W) {
-        camera_move_forward(camera, camera_move_speed, move_in_world_plane);
+        let orbit = camera.section("orbit")?;
+        let (mut min_pitch, mut max_pitch) = (controller.orbit.min_pitch.to_degrees(), controller.orbit.max_pitch.to_degrees());
+        orbit.f32("min_pitch_degrees", &mut min_pitch)?;
+        orbit.f32("max_pitch_degrees", &mut max_pitch)?;
+        controller.orbit.min_pitch = min_pitch.to_radians();
+        controller.orbit.max_pitch = max_pitch.to_radians();
+        orbit.f32("pan_speed", &mut controller.orbit.pan_speed)?;
+        orbit.f32("zoom_speed", &mut controller.orbit.zoom_speed)?;
+
+        let bindings = camera.section("bindings")?;
+        let input = &mut controller.input;
+        for (name, key) in [
+            ("forward", &mut input.forward),
+            ("back", &mut input.back),
+            ("left", &mut input.left),
+            ("right", &mut input.right),
+            ("up", &mut input.up),
+            ("down", &mut input.down),
+            ("pitch_up", &mut input.pitch_up),
+            ("pitch_down", &mut input.pitch_down),
+            ("yaw_left", &mut input.yaw_left),
+            ("yaw_right", &mut input.yaw_right),
+            ("roll_left", &mut input.roll_left),
+            ("roll_right", &mut input.roll_right),
+            ("zoom_in", &mut input.zoom_in),
+            ("zoom_out", &mut input.zoom_out),
+            ("sprint", &mut input.sprint),
+        ] {
+            bindings.key(name, key)?;
+        }
+        Ok(controller)
     }
-    if is_key_down(KeyCode::A) {
-        camera_move_right(camera, -camera_move_speed, move_in_world_plane);
+
+    fn speed_factor(&self) -> f32 {
+        if is_key_down(self.input.sprint) { self.sprint_multiplier } else { 1.0 }
     }
-    if is_key_down(KeyCode::S) {
-        camera_move_forward(camera, -camera_move_speed, move_in_world_plane);
+
+    /// Converts a mouse movement in window units into yaw and pitch angles.
+    fn look_angles(&self, mouse_delta: Vec2) -> Vec2 {
+        0.5*mouse_delta*self.mouse_sensitivity
     }
-    if is_key_down(KeyCode::D) {
-        camera_move_right(camera, camera_move_speed, move_in_world_plane);
+
+    /// Reads this frame's free flight input from the keyboard and mouse.
+    pub fn sample_input(&self) -> CameraInput {
+        let input = &self.input;
+        let axis = |positive: KeyCode, negative: KeyCode| is_key_down(positive) as i32 as f32 - is_key_down(negative) as i32 as f32;
+        let mut zoom = -mouse_wheel().1;
+        if is_key_pressed(input.zoom_out) {
+            zoom += 2.0;
+        }
+        if is_key_pressed(input.zoom_in) {
+            zoom -= 2.0;
+        }
+        CameraInput {
+            dt: get_frame_time(),
+            movement: vec3(axis(input.right, input.left), axis(input.up, input.down), axis(input.forward, input.back)),
+            rotation: vec3(axis(input.yaw_left, input.yaw_right), axis(input.pitch_up, input.pitch_down), axis(input.roll_right, input.roll_left)),
+            mouse_delta: mouse_delta_position(),
+            pan: is_mouse_button_down(MouseButton::Left),
+            sprint: is_key_down(input.sprint),
+            zoom,
+        }
     }
 
-    if is_key_down(KeyCode::Space) {
-        camera_move_up(camera, camera_move_speed);
+    /// Free flight driven by the keyboard and mouse.
+    pub fn update(&mut self, camera: &mut Camera3D) {
+        let input = self.sample_input();
+        self.step(camera, &input);
     }
-    if is_key_down(KeyCode::LeftControl) {
-        camera_move_up(camera, -camera_move_speed);
+
+    /// Advances free flight by one frame. The result only depends on the controller state and
+    /// `input`, and the velocity follows the same curve at any frame rate.
+    pub fn step(&mut self, camera: &mut Camera3D, input: &CameraInput) {
+        let lock_view = self.lock_view;
+        let rotate_around_target = false;
+        let rotate_up = self.rotate_up;
+        let move_in_world_plane = self.move_in_world_plane;
+        let dt = input.dt;
+        let speed_factor = if input.sprint { self.sprint_multiplier } else { 1.0 };
+
+        let rotation = input.rotation*self.rotate_speed*dt;
+        camera_pitch(camera, rotation.y, rotate_around_target, lock_view, rotate_up);
+        camera_yaw(camera, rotation.x, rotate_around_target);
+        camera_roll(camera, rotation.z);
+
+        if input.pan {
+            // drag the world along with the mouse
+            let pan = 0.5*input.mouse_delta*self.pan_speed*speed_factor;
+            camera_move_right(camera, pan.x, move_in_world_plane);
+            camera_move_up(camera, -pan.y);
+        }
+        else {
+            self.pending_look += self.look_angles(input.mouse_delta);
+            let look = if self.look_smoothing > 0.0 {
+                self.pending_look*(1.0 - (-dt/self.look_smoothing).exp())
+            } else {
+                self.pending_look
+            };
+            self.pending_look -= look;
+            camera_yaw(camera, -look.x, rotate_around_target);
+            camera_pitch(camera, -look.y, rotate_around_target, lock_view, rotate_up);
+        }
+
+        // ease the velocity towards the held direction, exponentially so it does not depend on dt
+        let target_velocity = input.movement*self.move_speed*speed_factor;
+        let rate = if input.movement == Vec3::ZERO { self.damping } else { self.acceleration };
+        let decay = (-rate*dt).exp();
+        // the distance covered along that curve during the frame, not just the final velocity times dt
+        let easing = if rate > 0.0 { (1.0 - decay)/rate } else { dt };
+        let distance = target_velocity*dt + (self.velocity - target_velocity)*easing;
+        self.velocity = target_velocity + (self.velocity - target_velocity)*decay;
+        camera_move_right(camera, distance.x, move_in_world_plane);
+        camera_move_up(camera, distance.y);
+        camera_move_forward(camera, distance.z, move_in_world_plane);
+
+        camera_move_to_target(camera, input.zoom);
     }
 
-    // Mouse wheel zoom
-    camera_move_to_target(camera, -mouse_wheel().1);
+    /// Walks the camera over the surface described by `ground(x, z)`. Looking works as in free
+    /// flight, movement stays in the world plane and `up` jumps.
+    pub fn update_walk(&mut self, camera: &mut Camera3D, ground: impl Fn(f32, f32) -> f32) {
+        let dt = get_frame_time();
+        let camera_rotation_speed = self.rotate_speed*dt;
+        let input = &self.input;
+        camera.up = vec3(0., 1., 0.);
 
-    // Numpad zoom
-    if is_key_pressed(KeyCode::KpSubtract) {
-        camera_move_to_target(camera, 2.0);
+        if is_key_down(input.pitch_down) {
+            camera_pitch(camera, -camera_rotation_speed, false, true, false);
+        }
+        if is_key_down(input.pitch_up) {
+            camera_pitch(camera, camera_rotation_speed, false, true, false);
+        }
+        if is_key_down(input.yaw_right) {
+            camera_yaw(camera, -camera_rotation_speed, false);
+        }
+        if is_key_down(input.yaw_left) {
+            camera_yaw(camera, camera_rotation_speed, false);
+        }
+        let look = self.look_angles(mouse_delta_position());
+        camera_yaw(camera, -look.x, false);
+        camera_pitch(camera, -look.y, false, true, false);
+
+        let start = camera.position;
+        let distance = self.walker.walk_speed*self.speed_factor()*dt;
+        if is_key_down(input.forward) {
+            camera_move_forward(camera, distance, true);
+        }
+        if is_key_down(input.left) {
+            camera_move_right(camera, -distance, true);
+        }
+        if is_key_down(input.back) {
+            camera_move_forward(camera, -distance, true);
+        }
+        if is_key_down(input.right) {
+            camera_move_right(camera, distance, true);
+        }
+        let jump = is_key_pressed(input.up);
+        walk_step(camera, &mut self.walker, start, jump, dt, ground);
     }
-    if is_key_pressed(KeyCode::KpAdd) {
-        camera_move_to_target(camera, -2.0);
+
+    /// Rotates around the pivot by dragging with the left mouse button or with the look keys,
+    /// zooms with the mouse wheel and pans the pivot along the `ground(x, z)` surface.
+    pub fn update_orbit(&self, camera: &mut Camera3D, ground: impl Fn(f32, f32) -> f32) {
+        let dt = get_frame_time();
+        let camera_rotation_speed = self.rotate_speed*dt;
+        let input = &self.input;
+        let orbit = &self.orbit;
+        camera.up = vec3(0., 1., 0.);
+
+        let mut yaw = 0.0;
+        let mut pitch = 0.0;
+        if is_mouse_button_down(MouseButton::Left) {
+            let look = self.look_angles(mouse_delta_position());
+            yaw -= look.x;
+            pitch -= look.y;
+        }
+        if is_key_down(input.yaw_right) { yaw -= camera_rotation_speed; }
+        if is_key_down(input.yaw_left) { yaw += camera_rotation_speed; }
+        if is_key_down(input.pitch_down) { pitch -= camera_rotation_speed; }
+        if is_key_down(input.pitch_up) { pitch += camera_rotation_speed; }
+        camera_yaw(camera, yaw, true);
+        if pitch != 0.0 {
+            // how far the elevation lies outside the allowed range, pitching may only reduce it
+            let outside = |elevation: f32| (orbit.min_pitch-elevation).max(0.0) + (elevation-orbit.max_pitch).max(0.0);
+            let before = camera.position;
+            let outside_before = outside(camera_elevation(camera));
+            camera_pitch(camera, pitch, true, true, false);
+            let outside_after = outside(camera_elevation(camera));
+            if outside_after > 0.0 && outside_after >= outside_before {
+                camera.position = before;
+            }
+        }
+
+        camera_move_to_target(camera, -mouse_wheel().1*orbit.zoom_speed);
+        if is_key_pressed(input.zoom_out) {
+            camera_move_to_target(camera, 2.0);
+        }
+        if is_key_pressed(input.zoom_in) {
+            camera_move_to_target(camera, -2.0);
+        }
+
+        let distance = orbit.pan_speed*self.speed_factor()*dt;
+        if is_key_down(input.forward) {
+            camera_move_forward(camera, distance, true);
+        }
+        if is_key_down(input.left) {
+            camera_move_right(camera, -distance, true);
+        }
+        if is_key_down(input.back) {
+            camera_move_forward(camera, -distance, true);
+        }
+        if is_key_down(input.right) {
+            camera_move_right(camera, distance, true);
+        }
+        let pivot = vec3(camera.target.x, ground(camera.target.x, camera.target.z), camera.target.z);
+        camera_set_pivot(camera, pivot);
     }
 }
 
@@ -210,3 +644,139 @@ pub fn update_camera(camera: &mut Camera3D) {
         CameraMoveToTarget(camera, -GetMouseWheelMove());
         if (IsKeyPressed(KEY_KP_SUBTRACT)) CameraMoveToTarget(camera, 2.0f);
         if (IsKeyPressed(KEY_KP_ADD)) CameraMoveToTarget(camera, -2.0f); */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ray_from_ndc_orthographic() {
+        // looking down -z from the origin at a 4 by 2 window, 0.5 to 10 units deep
+        let view_projection = Mat4::orthographic_rh_gl(-2.0, 2.0, -1.0, 1.0, 0.5, 10.0);
+        let (origin, dir) = ray_from_ndc(view_projection, Vec2::new(0.5, 0.5));
+        assert!(origin.abs_diff_eq(Vec3::new(1.0, 0.5, -0.5), 1e-5), "{origin}");
+        assert!(dir.abs_diff_eq(Vec3::NEG_Z, 1e-5), "{dir}");
+    }
+
+    #[test]
+    fn ray_from_ndc_perspective() {
+        let (eye, target) = (Vec3::new(3.0, 2.0, 1.0), Vec3::new(0.0, 0.5, -1.0));
+        let view = Mat4::look_at_rh(eye, target, Vec3::Y);
+        let view_projection = Mat4::perspective_rh_gl(1.0, 1.5, 0.1, 100.0) * view;
+        // through the center of the screen, straight ahead from the near plane
+        let forward = (target - eye).normalize();
+        let (origin, dir) = ray_from_ndc(view_projection, Vec2::ZERO);
+        assert!(origin.abs_diff_eq(eye + 0.1 * forward, 1e-4), "{origin}");
+        assert!(dir.abs_diff_eq(forward, 1e-4), "{dir}");
+        // through the top right corner, which lies at half the field of view above the center
+        let (_, dir) = ray_from_ndc(view_projection, Vec2::ONE);
+        let local = view.transform_vector3(dir);
+        assert!((local.y / -local.z - 0.5f32.tan()).abs() < 1e-4, "{local}");
+        assert!((local.x / -local.z - 1.5 * 0.5f32.tan()).abs() < 1e-4, "{local}");
+    }
+
+    /// Walks `distance` along +x in one step of `dt` seconds over `ground`.
+    fn walk(camera: &mut Camera3D, walker: &mut Walker, distance: f32, dt: f32, ground: impl Fn(f32, f32) -> f32) {
+        let start = camera.position;
+        camera.position.x += distance;
+        camera.target.x += distance;
+        walk_step(camera, walker, start, false, dt, ground);
+    }
+
+    fn standing(x: f32, ground: impl Fn(f32, f32) -> f32) -> (Camera3D, Walker) {
+        let walker = Walker { grounded: true, ..Walker::default() };
+        let position = vec3(x, ground(x, 0.0) + walker.eye_height, 0.0);
+        (Camera3D { position, target: position + Vec3::X, ..Default::default() }, walker)
+    }
+
+    #[test]
+    fn walking_off_a_ledge_falls() {
+        let cliff = |x: f32, _: f32| if x < 1.0 { 1.0 } else { 0.0 };
+        let (mut camera, mut walker) = standing(0.995, cliff);
+        walk(&mut camera, &mut walker, 0.01, 1.0 / 60.0, cliff);
+        assert!(!walker.grounded);
+        assert!(camera.position.y > 1.0, "{}", camera.position.y);
+        // gravity takes about a second for the drop
+        let mut steps = 0;
+        while !walker.grounded {
+            walk(&mut camera, &mut walker, 0.0, 1.0 / 60.0, cliff);
+            steps += 1;
+            assert!(steps < 120);
+        }
+        assert!(steps > 45, "{steps}");
+        assert_eq!(camera.position.y, walker.eye_height);
+    }
+
+    #[test]
+    fn walking_downhill_and_down_small_steps_sticks_to_the_ground() {
+        let slope = |x: f32, _: f32| -x;
+        let (mut camera, mut walker) = standing(0.0, slope);
+        for _ in 0..60 {
+            walk(&mut camera, &mut walker, 0.005, 1.0 / 60.0, slope);
+            assert!(walker.grounded);
+            assert_eq!(camera.position.y, slope(camera.position.x, 0.0) + walker.eye_height);
+        }
+        let stairs = |x: f32, _: f32| -0.005 * (x / 0.1).floor();
+        let (mut camera, mut walker) = standing(0.0, stairs);
+        for _ in 0..60 {
+            walk(&mut camera, &mut walker, 0.005, 1.0 / 60.0, stairs);
+            assert!(walker.grounded);
+        }
+    }
+
+    fn test_camera() -> Camera3D {
+        Camera3D { position: vec3(0.0, 1.0, 0.0), target: vec3(0.0, 1.0, -1.0), up: Vec3::Y, ..Default::default() }
+    }
+
+    /// Holds forward and right for `hold` seconds, then lets go for `coast` seconds, at `rate` steps per second.
+    fn replay(rate: u32, hold: f32, coast: f32) -> (Camera3D, CameraController) {
+        let (mut camera, mut controller) = (test_camera(), CameraController::default());
+        let dt = 1.0 / rate as f32;
+        let moving = CameraInput { dt, movement: vec3(1.0, 0.0, 1.0), ..Default::default() };
+        let released = CameraInput { dt, ..Default::default() };
+        for _ in 0..(hold * rate as f32).round() as u32 {
+            controller.step(&mut camera, &moving);
+        }
+        for _ in 0..(coast * rate as f32).round() as u32 {
+            controller.step(&mut camera, &released);
+        }
+        (camera, controller)
+    }
+
+    #[test]
+    fn step_does_not_depend_on_the_frame_rate() {
+        for (hold, coast) in [(0.5, 0.0), (1.0, 0.5), (2.0, 2.0)] {
+            let (slow, slow_controller) = replay(30, hold, coast);
+            let (fast, fast_controller) = replay(120, hold, coast);
+            assert!(slow.position.abs_diff_eq(fast.position, 1e-4), "{} vs {}", slow.position, fast.position);
+            assert!(slow.target.abs_diff_eq(fast.target, 1e-4), "{} vs {}", slow.target, fast.target);
+            assert!(slow_controller.velocity.abs_diff_eq(fast_controller.velocity, 1e-4));
+        }
+    }
+
+    #[test]
+    fn velocity_decays_after_release() {
+        let (_, controller) = replay(60, 1.0, 0.0);
+        assert!(controller.velocity.length() > 0.9);
+        let (mut camera, mut controller) = replay(60, 1.0, 5.0);
+        assert!(controller.velocity.length() < 1e-5, "{}", controller.velocity);
+        let before = camera.position;
+        controller.step(&mut camera, &CameraInput { dt: 1.0 / 60.0, ..Default::default() });
+        assert!(camera.position.distance(before) < 1e-6);
+    }
+
+    #[test]
+    fn pan_distance_grows_with_the_mouse_delta() {
+        let pan = |delta: f32| {
+            let (mut camera, mut controller) = (test_camera(), CameraController::default());
+            let input = CameraInput { dt: 1.0 / 60.0, mouse_delta: vec2(delta, 0.0), pan: true, ..Default::default() };
+            controller.step(&mut camera, &input);
+            camera.position.distance(test_camera().position)
+        };
+        let distances = [0.05, 0.1, 0.2, 0.4].map(pan);
+        assert!(distances[0] > 0.0);
+        for pair in distances.windows(2) {
+            assert!((pair[1] - 2.0 * pair[0]).abs() < 1e-5, "{distances:?}");
+        }
+    }
+}
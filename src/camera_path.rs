@@ -0,0 +1,133 @@
+use macroquad::prelude::*;
+use toml::{Table, Value};
+
+/// Default file for recorded camera paths.
+pub const CAMERA_PATH_FILE: &str = "camera_path.toml";
+
+#[derive(Clone, Copy, Debug)]
+pub struct Keyframe {
+    /// Seconds since the start of the path.
+    pub time: f32,
+    pub position: Vec3,
+    pub target: Vec3,
+    pub up: Vec3,
+}
+
+impl Keyframe {
+    fn orientation(&self) -> Quat {
+        let forward = (self.target-self.position).normalize();
+        let right = forward.cross(self.up).normalize();
+        let up = right.cross(forward);
+        Quat::from_mat3(&Mat3::from_cols(right, up, -forward)).normalize()
+    }
+}
+
+/// A recorded flythrough. Positions are interpolated with a Catmull-Rom spline through the
+/// keyframes, orientations with slerp.
+#[derive(Clone, Debug, Default)]
+pub struct CameraPath {
+    pub keyframes: Vec<Keyframe>,
+}
+
+impl CameraPath {
+    pub fn record(&mut self, camera: &Camera3D, time: f32) {
+        self.keyframes.push(Keyframe { time, position: camera.position, target: camera.target, up: camera.up });
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.keyframes.last().map_or(0.0, |keyframe| keyframe.time)
+    }
+
+    /// Moves `camera` to where the path is at `time`. Returns false if the path is empty.
+    pub fn apply(&self, camera: &mut Camera3D, time: f32) -> bool {
+        let Some(keyframe) = self.sample(time) else { return false };
+        camera.position = keyframe.position;
+        camera.target = keyframe.target;
+        camera.up = keyframe.up;
+        true
+    }
+
+    pub fn sample(&self, time: f32) -> Option<Keyframe> {
+        let keyframes = &self.keyframes;
+        let last = keyframes.len().checked_sub(1)?;
+        if time <= keyframes[0].time {
+            return Some(keyframes[0]);
+        }
+        if time >= keyframes[last].time {
+            return Some(keyframes[last]);
+        }
+        let next = keyframes.partition_point(|keyframe| keyframe.time <= time);
+        let (k1, k2) = (keyframes[next - 1], keyframes[next]);
+        let k0 = keyframes[next.saturating_sub(2)];
+        let k3 = keyframes[(next + 1).min(last)];
+        let span = k2.time - k1.time;
+        let t = if span > 0.0 { (time - k1.time) / span } else { 1.0 };
+
+        let position = catmull_rom(k0.position, k1.position, k2.position, k3.position, t);
+        let orientation = k1.orientation().slerp(k2.orientation(), t);
+        let distance = (k1.target-k1.position).length().lerp((k2.target-k2.position).length(), t);
+        Some(Keyframe {
+            time,
+            position,
+            target: position + distance*(orientation*Vec3::NEG_Z),
+            up: orientation*Vec3::Y,
+        })
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let vector = |v: Vec3| Value::Array(vec![Value::Float(v.x as f64), Value::Float(v.y as f64), Value::Float(v.z as f64)]);
+        let keyframes = self.keyframes.iter().map(|keyframe| {
+            let mut table = Table::new();
+            table.insert("time".into(), Value::Float(keyframe.time as f64));
+            table.insert("position".into(), vector(keyframe.position));
+            table.insert("target".into(), vector(keyframe.target));
+            table.insert("up".into(), vector(keyframe.up));
+            Value::Table(table)
+        }).collect();
+        let mut document = Table::new();
+        document.insert("keyframe".into(), Value::Array(keyframes));
+        std::fs::write(path, document.to_string()).map_err(|err| format!("{path}: {err}"))
+    }
+
+    pub fn load(path: &str) -> Result<CameraPath, String> {
+        let text = std::fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+        let document: Table = text.parse().map_err(|err| format!("{path}: {err}"))?;
+        let number = |value: &Value, what: &str| match value {
+            Value::Float(v) => Ok(*v as f32),
+            Value::Integer(v) => Ok(*v as f32),
+            _ => Err(format!("{path}: {what}: expected a number")),
+        };
+        let vector = |table: &Table, key: &str, index: usize| -> Result<Vec3, String> {
+            let what = format!("keyframe[{index}].{key}");
+            match table.get(key) {
+                Some(Value::Array(values)) if values.len() == 3 => {
+                    Ok(vec3(number(&values[0], &what)?, number(&values[1], &what)?, number(&values[2], &what)?))
+                }
+                _ => Err(format!("{path}: {what}: expected an array of three numbers")),
+            }
+        };
+        let mut camera_path = CameraPath::default();
+        let Some(Value::Array(keyframes)) = document.get("keyframe") else {
+            return Ok(camera_path);
+        };
+        for (index, keyframe) in keyframes.iter().enumerate() {
+            let Value::Table(table) = keyframe else {
+                return Err(format!("{path}: keyframe[{index}]: expected a table"));
+            };
+            let time = table.get("time").ok_or_else(|| format!("{path}: keyframe[{index}].time: missing"))?;
+            camera_path.keyframes.push(Keyframe {
+                time: number(time, &format!("keyframe[{index}].time"))?,
+                position: vector(table, "position", index)?,
+                target: vector(table, "target", index)?,
+                up: vector(table, "up", index)?,
+            });
+        }
+        camera_path.keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+        Ok(camera_path)
+    }
+}
+
+pub fn catmull_rom(p0: Vec3, p1: Vec3, p2: Vec3, p3: Vec3, t: f32) -> Vec3 {
+    let (t2, t3) = (t*t, t*t*t);
+    0.5*(2.0*p1 + (p2-p0)*t + (2.0*p0 - 5.0*p1 + 4.0*p2 - p3)*t2 + (3.0*p1 - p0 - 3.0*p2 + p3)*t3)
+}
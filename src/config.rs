@@ -0,0 +1,271 @@
+use macroquad::prelude::*;
+use toml::{Table, Value};
+
+/// Settings file read at startup from the working directory. Every key in it is optional.
+pub const CONFIG_PATH: &str = "dirtjam.toml";
+
+/// Reads and parses the config file. A missing file is the same as an empty one.
+pub fn load_config(path: &str) -> Result<Table, String> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => text.parse::<Table>().map_err(|err| format!("{path}: {err}")),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Table::new()),
+        Err(err) => Err(format!("{path}: {err}")),
+    }
+}
+
+/// Polls the modification time of a file.
+pub struct FileWatcher {
+    pub path: String,
+    modified: Option<std::time::SystemTime>,
+    last_check: f64,
+}
+
+impl FileWatcher {
+    /// Seconds between two looks at the file.
+    const INTERVAL: f64 = 0.5;
+
+    pub fn new(path: &str) -> FileWatcher {
+        let modified = std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+        FileWatcher { path: path.to_string(), modified, last_check: get_time() }
+    }
+
+    /// Returns true once after every change to the file.
+    pub fn changed(&mut self) -> bool {
+        if get_time() - self.last_check < Self::INTERVAL {
+            return false;
+        }
+        self.last_check = get_time();
+        let modified = std::fs::metadata(&self.path).and_then(|metadata| metadata.modified()).ok();
+        if modified.is_some() && modified != self.modified {
+            self.modified = modified;
+            return true;
+        }
+        false
+    }
+}
+
+/// Command line options.
+#[derive(Clone, Debug, Default)]
+pub struct Args {
+    /// `--seed <n>`: seed of the terrain generator, random if missing.
+    pub seed: Option<u64>,
+    /// `--terrain <file>`: terrain description to build the generator from.
+    pub terrain: Option<String>,
+    /// `--export <file>`, repeatable: writes the heights of `region` to the file and exits
+    /// without opening a window, see `export_region`.
+    pub export: Vec<String>,
+    /// `--region <x0>,<z0>,<x1>,<z1>`: world space rectangle to export.
+    pub region: Option<(Vec2, Vec2)>,
+    /// `--resolution <width>[x<height>]`: samples of the export, the chunk mesh spacing if missing.
+    pub resolution: Option<(usize, usize)>,
+}
+
+impl Args {
+    /// Parses the arguments following the program name.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Args, String> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("{arg}: missing value"));
+            match arg.as_str() {
+                "--seed" => {
+                    let value = value()?;
+                    parsed.seed = Some(value.parse().map_err(|_| format!("--seed: {value:?} is not a non-negative integer"))?);
+                }
+                "--terrain" => parsed.terrain = Some(value()?),
+                "--export" => parsed.export.push(value()?),
+                "--region" => {
+                    let value = value()?;
+                    let numbers: Vec<f32> = value.split(',').map(|number| number.trim().parse()).collect::<Result<_, _>>()
+                        .map_err(|_| format!("--region: {value:?} is not a list of numbers"))?;
+                    match numbers[..] {
+                        [x0, z0, x1, z1] if x1 > x0 && z1 > z0 => parsed.region = Some((Vec2::new(x0, z0), Vec2::new(x1, z1))),
+                        _ => return Err(format!("--region: expected x0,z0,x1,z1 with x0 < x1 and z0 < z1, not {value:?}")),
+                    }
+                }
+                "--resolution" => {
+                    let value = value()?;
+                    let (width, height) = value.split_once('x').unwrap_or((&value, &value));
+                    match (width.parse(), height.parse()) {
+                        (Ok(width), Ok(height)) if width >= 2 && height >= 2 => parsed.resolution = Some((width, height)),
+                        _ => return Err(format!("--resolution: expected <width>x<height> or one size of at least 2, not {value:?}")),
+                    }
+                }
+                _ => return Err(format!("unknown argument {arg:?}")),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// A table of the config file. The getters leave the passed value untouched if the key is
+/// missing and report wrongly typed values with their full path.
+pub struct Section<'a> {
+    pub path: String,
+    table: Option<&'a Table>,
+}
+
+impl<'a> Section<'a> {
+    pub fn root(table: &'a Table) -> Section<'a> {
+        Section { path: String::new(), table: Some(table) }
+    }
+
+    fn key_path(&self, key: &str) -> String {
+        if self.path.is_empty() { key.to_string() } else { format!("{}.{}", self.path, key) }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&'a Value> {
+        self.table.and_then(|table| table.get(key))
+    }
+
+    /// A nested table. Missing tables behave like empty ones.
+    pub fn section(&self, key: &str) -> Result<Section<'a>, String> {
+        let path = self.key_path(key);
+        match self.get(key) {
+            None => Ok(Section { path, table: None }),
+            Some(Value::Table(table)) => Ok(Section { path, table: Some(table) }),
+            Some(_) => Err(format!("{path}: expected a table")),
+        }
+    }
+
+    pub fn f64(&self, key: &str, value: &mut f64) -> Result<(), String> {
+        match self.get(key) {
+            None => {}
+            Some(Value::Float(v)) => *value = *v,
+            Some(Value::Integer(v)) => *value = *v as f64,
+            Some(_) => return Err(format!("{}: expected a number", self.key_path(key))),
+        }
+        Ok(())
+    }
+
+    pub fn f32(&self, key: &str, value: &mut f32) -> Result<(), String> {
+        let mut v = *value as f64;
+        self.f64(key, &mut v)?;
+        *value = v as f32;
+        Ok(())
+    }
+
+    pub fn usize(&self, key: &str, value: &mut usize) -> Result<(), String> {
+        match self.get(key) {
+            None => {}
+            Some(Value::Integer(v)) if *v >= 0 => *value = *v as usize,
+            Some(_) => return Err(format!("{}: expected a non-negative integer", self.key_path(key))),
+        }
+        Ok(())
+    }
+
+    pub fn bool(&self, key: &str, value: &mut bool) -> Result<(), String> {
+        match self.get(key) {
+            None => {}
+            Some(Value::Boolean(v)) => *value = *v,
+            Some(_) => return Err(format!("{}: expected true or false", self.key_path(key))),
+        }
+        Ok(())
+    }
+
+    pub fn str(&self, key: &str) -> Result<Option<&'a str>, String> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::String(v)) => Ok(Some(v)),
+            Some(_) => Err(format!("{}: expected a string", self.key_path(key))),
+        }
+    }
+
+    pub fn key(&self, key: &str, value: &mut KeyCode) -> Result<(), String> {
+        if let Some(name) = self.str(key)? {
+            *value = key_code(name).ok_or_else(|| format!("{}: unknown key {name:?}", self.key_path(key)))?;
+        }
+        Ok(())
+    }
+}
+
+/// Looks up a key by the name of its `KeyCode` variant, e.g. `"W"`, `"Space"` or `"LeftShift"`.
+pub fn key_code(name: &str) -> Option<KeyCode> {
+    use KeyCode::*;
+    let letters = [A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z];
+    let digits = [Key0, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9];
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        if c.is_ascii_alphabetic() {
+            return Some(letters[(c.to_ascii_uppercase() as u8 - b'A') as usize]);
+        }
+        if c.is_ascii_digit() {
+            return Some(digits[(c as u8 - b'0') as usize]);
+        }
+    }
+    Some(match name {
+        "Space" => Space,
+        "Enter" => Enter,
+        "Tab" => Tab,
+        "Backspace" => Backspace,
+        "Up" => Up,
+        "Down" => Down,
+        "Left" => Left,
+        "Right" => Right,
+        "PageUp" => PageUp,
+        "PageDown" => PageDown,
+        "Home" => Home,
+        "End" => End,
+        "Insert" => Insert,
+        "Delete" => Delete,
+        "LeftShift" => LeftShift,
+        "RightShift" => RightShift,
+        "LeftControl" => LeftControl,
+        "RightControl" => RightControl,
+        "LeftAlt" => LeftAlt,
+        "RightAlt" => RightAlt,
+        "KpAdd" => KpAdd,
+        "KpSubtract" => KpSubtract,
+        "KpMultiply" => KpMultiply,
+        "KpDivide" => KpDivide,
+        "KpEnter" => KpEnter,
+        "Kp0" => Kp0,
+        "Kp1" => Kp1,
+        "Kp2" => Kp2,
+        "Kp3" => Kp3,
+        "Kp4" => Kp4,
+        "Kp5" => Kp5,
+        "Kp6" => Kp6,
+        "Kp7" => Kp7,
+        "Kp8" => Kp8,
+        "Kp9" => Kp9,
+        "Minus" => Minus,
+        "Equal" => Equal,
+        "Comma" => Comma,
+        "Period" => Period,
+        "Slash" => Slash,
+        "Semicolon" => Semicolon,
+        "Apostrophe" => Apostrophe,
+        "LeftBracket" => LeftBracket,
+        "RightBracket" => RightBracket,
+        "Backslash" => Backslash,
+        "GraveAccent" => GraveAccent,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Args, String> {
+        Args::parse(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn parses_the_export_arguments() {
+        let args = parse("--export a.png --region -1,2.5,3,4 --resolution 65x33 --export b.f32").unwrap();
+        assert_eq!(args.export, ["a.png", "b.f32"]);
+        assert_eq!(args.region, Some((Vec2::new(-1.0, 2.5), Vec2::new(3.0, 4.0))));
+        assert_eq!(args.resolution, Some((65, 33)));
+        assert_eq!(parse("--resolution 129").unwrap().resolution, Some((129, 129)));
+        assert_eq!(parse("").unwrap().region, None);
+    }
+
+    #[test]
+    fn rejects_bad_export_arguments() {
+        for args in ["--region 0,0,1", "--region 1,0,0,1", "--region 0,0,a,1", "--resolution 1", "--resolution 8x", "--region"] {
+            assert!(parse(args).is_err(), "{args}");
+        }
+    }
+}
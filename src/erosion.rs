@@ -0,0 +1,313 @@
+use macroquad::prelude::*;
+use toml::Value;
+
+use crate::config::Section;
+use crate::grid::HeightGrid;
+
+/// A step that reshapes sampled heights before chunk meshes are built from them. Stages run
+/// in order on a grid at the resolution of the most detailed LOD.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErosionStage {
+    Hydraulic(HydraulicErosion),
+    Thermal(ThermalErosion),
+}
+
+impl ErosionStage {
+    /// Grid cells around a chunk that are simulated along with it, so that material moving
+    /// across the chunk border is accounted for on both sides. Erosion fades out over half this
+    /// distance towards the chunk borders, where neighbours have to agree exactly.
+    pub fn halo(&self) -> usize {
+        match self {
+            ErosionStage::Hydraulic(erosion) => erosion.halo,
+            // material moves at most one cell per iteration
+            ErosionStage::Thermal(erosion) => erosion.iterations,
+        }
+    }
+
+    /// Runs the stage on `grid`, whose point `(0, 0)` is the world grid point `origin`. Random
+    /// choices only depend on `seed` and world grid positions, so overlapping grids of
+    /// neighbouring chunks see the same droplets. `cell_size` is the distance between grid
+    /// points in units of the heights, for stages that care about angles.
+    pub fn apply(&self, grid: &mut HeightGrid, origin: IVec2, seed: u64, cell_size: f32) {
+        match self {
+            ErosionStage::Hydraulic(erosion) => erosion.apply(grid, origin, seed),
+            ErosionStage::Thermal(erosion) => erosion.apply(grid, cell_size),
+        }
+    }
+
+    /// Reads the stage names from `stages` in the `[erosion]` table and the parameters of each
+    /// stage from the table of the same name below it, e.g. `[erosion.hydraulic]`.
+    pub fn from_config(config: &Section) -> Result<Vec<ErosionStage>, String> {
+        let erosion = config.section("erosion")?;
+        let names = match erosion.get("stages") {
+            None => return Ok(Vec::new()),
+            Some(Value::Array(names)) => names,
+            Some(_) => return Err(format!("{}.stages: expected an array of stage names", erosion.path)),
+        };
+        names.iter().enumerate().map(|(index, name)| match name.as_str() {
+            Some("hydraulic") => Ok(ErosionStage::Hydraulic(HydraulicErosion::from_config(&erosion.section("hydraulic")?)?)),
+            Some("thermal") => Ok(ErosionStage::Thermal(ThermalErosion::from_config(&erosion.section("thermal")?)?)),
+            Some(name) => Err(format!("{}.stages[{index}]: unknown erosion stage {name:?}", erosion.path)),
+            None => Err(format!("{}.stages[{index}]: expected a string", erosion.path)),
+        }).collect()
+    }
+}
+
+/// Particle based hydraulic erosion: droplets run downhill, pick up material where they speed
+/// up and drop it where they slow down or evaporate, after Hans Theobald Beyer, "Implementation
+/// of a method for hydraulic erosion". Grid points are one unit apart, heights are in the
+/// `[0, 1]` range of `terrain_height`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HydraulicErosion {
+    /// Droplets started per grid cell. Fractions start a droplet in that share of the cells.
+    pub droplets_per_cell: f32,
+    /// Steps after which a droplet is dropped.
+    pub max_lifetime: usize,
+    /// How much of its direction a droplet keeps instead of following the slope, 0 to 1.
+    pub inertia: f32,
+    /// Sediment carried per unit of height lost, speed and water.
+    pub sediment_capacity: f32,
+    /// Lower bound of the capacity, so droplets keep eroding on flat ground.
+    pub min_sediment_capacity: f32,
+    /// Share of the free capacity picked up per step.
+    pub erosion_rate: f32,
+    /// Share of the excess sediment dropped per step.
+    pub deposition_rate: f32,
+    /// Share of the water lost per step.
+    pub evaporation: f32,
+    pub gravity: f32,
+    /// See `ErosionStage::halo`. Droplets move one cell per step, so droplets from up to
+    /// `max_lifetime` cells away can reach the chunk.
+    pub halo: usize,
+}
+
+impl Default for HydraulicErosion {
+    fn default() -> Self {
+        HydraulicErosion {
+            droplets_per_cell: 1.0,
+            max_lifetime: 30,
+            inertia: 0.05,
+            sediment_capacity: 4.0,
+            min_sediment_capacity: 0.01,
+            erosion_rate: 0.3,
+            deposition_rate: 0.3,
+            evaporation: 0.01,
+            gravity: 4.0,
+            halo: 16,
+        }
+    }
+}
+
+impl HydraulicErosion {
+    pub fn from_config(config: &Section) -> Result<HydraulicErosion, String> {
+        let mut erosion = HydraulicErosion::default();
+        config.f32("droplets_per_cell", &mut erosion.droplets_per_cell)?;
+        config.usize("max_lifetime", &mut erosion.max_lifetime)?;
+        config.f32("inertia", &mut erosion.inertia)?;
+        config.f32("sediment_capacity", &mut erosion.sediment_capacity)?;
+        config.f32("min_sediment_capacity", &mut erosion.min_sediment_capacity)?;
+        config.f32("erosion_rate", &mut erosion.erosion_rate)?;
+        config.f32("deposition_rate", &mut erosion.deposition_rate)?;
+        config.f32("evaporation", &mut erosion.evaporation)?;
+        config.f32("gravity", &mut erosion.gravity)?;
+        config.usize("halo", &mut erosion.halo)?;
+        Ok(erosion)
+    }
+
+    /// See `ErosionStage::apply`. Droplets are started cell by cell in world order.
+    pub fn apply(&self, grid: &mut HeightGrid, origin: IVec2, seed: u64) {
+        if grid.width < 2 || grid.height < 2 {
+            return;
+        }
+        let (whole, fraction) = (self.droplets_per_cell.floor() as u32, self.droplets_per_cell.fract());
+        for y in 0..grid.height - 1 {
+            for x in 0..grid.width - 1 {
+                let (world_x, world_y) = (origin.x + x as i32, origin.y + y as i32);
+                let count = whole + (random(seed, world_x, world_y, 0) < fraction) as u32;
+                for n in 0..count {
+                    let jitter = Vec2::new(random(seed, world_x, world_y, 2 * n + 1), random(seed, world_x, world_y, 2 * n + 2));
+                    self.droplet(grid, Vec2::new(x as f32, y as f32) + jitter);
+                }
+            }
+        }
+    }
+
+    fn droplet(&self, grid: &mut HeightGrid, mut position: Vec2) {
+        let (max_x, max_y) = ((grid.width - 1) as f32, (grid.height - 1) as f32);
+        let (mut direction, mut speed, mut water, mut sediment) = (Vec2::ZERO, 1.0f32, 1.0f32, 0.0f32);
+        for _ in 0..self.max_lifetime {
+            let (height, gradient) = grid.height_and_gradient(position.x, position.y);
+            direction = direction * self.inertia - gradient * (1.0 - self.inertia);
+            if direction == Vec2::ZERO {
+                break;
+            }
+            let previous = position;
+            position += direction.normalize();
+            if position.x < 0.0 || position.y < 0.0 || position.x >= max_x || position.y >= max_y {
+                break;
+            }
+            let delta = grid.height_and_gradient(position.x, position.y).0 - height;
+            let capacity = (-delta * speed * water * self.sediment_capacity).max(self.min_sediment_capacity);
+            if delta > 0.0 || sediment > capacity {
+                // fill the pit that was climbed out of, or drop what cannot be carried
+                let amount = if delta > 0.0 { delta.min(sediment) } else { (sediment - capacity) * self.deposition_rate };
+                sediment -= amount;
+                spread(grid, previous, amount);
+            } else {
+                // never dig deeper than the step down, that would cut holes into the slope
+                let amount = ((capacity - sediment) * self.erosion_rate).min(-delta);
+                sediment += amount;
+                spread(grid, previous, -amount);
+            }
+            speed = (speed * speed - delta * self.gravity).max(0.0).sqrt();
+            water *= 1.0 - self.evaporation;
+        }
+    }
+}
+
+/// Thermal weathering: wherever the ground is steeper than the talus angle, material slides
+/// down to the lower neighbours until the slope settles at that angle, leaving scree slopes
+/// below cliffs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ThermalErosion {
+    /// Steepest slope in degrees that loose material rests on.
+    pub talus_angle: f32,
+    pub iterations: usize,
+    /// Share of the excess height moved per iteration, 0 to 1.
+    pub rate: f32,
+}
+
+impl Default for ThermalErosion {
+    fn default() -> Self {
+        ThermalErosion { talus_angle: 35.0, iterations: 16, rate: 0.5 }
+    }
+}
+
+impl ThermalErosion {
+    pub fn from_config(config: &Section) -> Result<ThermalErosion, String> {
+        let mut erosion = ThermalErosion::default();
+        config.f32("talus_angle", &mut erosion.talus_angle)?;
+        config.usize("iterations", &mut erosion.iterations)?;
+        config.f32("rate", &mut erosion.rate)?;
+        Ok(erosion)
+    }
+
+    /// See `ErosionStage::apply`. All points move material at the same time, so the result
+    /// does not depend on the order they are visited in.
+    pub fn apply(&self, grid: &mut HeightGrid, cell_size: f32) {
+        let talus = self.talus_angle.to_radians().tan() * cell_size;
+        let neighbours = [(-1, 0), (1, 0), (0, -1), (0, 1)];
+        let mut moved = HeightGrid::new(grid.width, grid.height);
+        for _ in 0..self.iterations {
+            moved.data.fill(0.0);
+            for y in 0..grid.height {
+                for x in 0..grid.width {
+                    let height = grid.get(x, y);
+                    let lower = neighbours.map(|(dx, dy)| {
+                        let (nx, ny) = (x.wrapping_add_signed(dx), y.wrapping_add_signed(dy));
+                        if nx >= grid.width || ny >= grid.height {
+                            return None;
+                        }
+                        let excess = height - grid.get(nx, ny) - talus;
+                        (excess > 0.0).then_some((nx, ny, excess))
+                    });
+                    let (total, steepest) = lower.iter().flatten().fold((0.0f32, 0.0f32), |(total, steepest), &(_, _, excess)| {
+                        (total + excess, steepest.max(excess))
+                    });
+                    if total == 0.0 {
+                        continue;
+                    }
+                    // half the excess evens out the steepest drop, shared in proportion to the drops
+                    let amount = self.rate * steepest / 2.0;
+                    *moved.get_mut(x, y) -= amount;
+                    for &(nx, ny, excess) in lower.iter().flatten() {
+                        *moved.get_mut(nx, ny) += amount * excess / total;
+                    }
+                }
+            }
+            for (height, moved) in grid.data.iter_mut().zip(&moved.data) {
+                *height += moved;
+            }
+        }
+    }
+}
+
+/// Adds `amount` to the four grid points around `position`, weighted by their closeness.
+fn spread(grid: &mut HeightGrid, position: Vec2, amount: f32) {
+    let (x, y) = (position.x as usize, position.y as usize);
+    let (s, t) = (position.x - x as f32, position.y - y as f32);
+    *grid.get_mut(x, y) += amount * (1.0 - s) * (1.0 - t);
+    *grid.get_mut(x + 1, y) += amount * s * (1.0 - t);
+    *grid.get_mut(x, y + 1) += amount * (1.0 - s) * t;
+    *grid.get_mut(x + 1, y + 1) += amount * s * t;
+}
+
+/// Deterministic random number in `[0, 1)` for the `n`th choice at a world grid position.
+fn random(seed: u64, x: i32, y: i32, n: u32) -> f32 {
+    let mut hash = seed;
+    for value in [x as u32 as u64, y as u32 as u64, n as u64] {
+        hash = splitmix64(hash ^ value);
+    }
+    (hash >> 40) as f32 / (1u64 << 24) as f32
+}
+
+fn splitmix64(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_grid(seed: u64) -> HeightGrid {
+        HeightGrid::from_fn(12, 12, |x, y| 5.0 * random(seed, x as i32, y as i32, 0))
+    }
+
+    fn max_slope(grid: &HeightGrid) -> f32 {
+        let mut slope = 0.0f32;
+        for y in 0..grid.height {
+            for x in 0..grid.width {
+                if x + 1 < grid.width { slope = slope.max((grid.get(x, y) - grid.get(x + 1, y)).abs()); }
+                if y + 1 < grid.height { slope = slope.max((grid.get(x, y) - grid.get(x, y + 1)).abs()); }
+            }
+        }
+        slope
+    }
+
+    #[test]
+    fn thermal_max_slope_never_increases() {
+        let step = ThermalErosion { iterations: 1, ..ThermalErosion::default() };
+        for seed in 0..200 {
+            let mut grid = random_grid(seed);
+            let mut slope = max_slope(&grid);
+            for iteration in 0..32 {
+                step.apply(&mut grid, 1.0);
+                let next = max_slope(&grid);
+                assert!(next <= slope + 1e-5, "seed {seed}, iteration {iteration}: {slope} -> {next}");
+                slope = next;
+            }
+        }
+    }
+
+    #[test]
+    fn thermal_settles_towards_the_talus_angle() {
+        let erosion = ThermalErosion { iterations: 500, ..ThermalErosion::default() };
+        let mut grid = random_grid(7);
+        erosion.apply(&mut grid, 1.0);
+        assert!(max_slope(&grid) < erosion.talus_angle.to_radians().tan() + 0.05, "{}", max_slope(&grid));
+    }
+
+    #[test]
+    fn thermal_conserves_material() {
+        for seed in 0..20 {
+            let mut grid = random_grid(seed);
+            let before: f32 = grid.data.iter().sum();
+            ThermalErosion::default().apply(&mut grid, 0.5);
+            let after: f32 = grid.data.iter().sum();
+            assert!((after - before).abs() < 1e-3, "seed {seed}: {before} -> {after}");
+        }
+    }
+}
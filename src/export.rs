@@ -0,0 +1,109 @@
+use macroquad::prelude::*;
+use libnoise::prelude::*;
+
+use crate::grid::HeightGrid;
+use crate::raster::{save_png16, save_raw_f32, RasterFormat};
+use crate::tiles::TileCache;
+
+/// A world space rectangle in the horizontal plane and the size of the grid it is sampled on.
+/// The corner samples lie exactly on the corners of the rectangle.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExportRegion {
+    pub min: Vec2,
+    pub max: Vec2,
+    pub resolution: (usize, usize),
+}
+
+impl ExportRegion {
+    /// The grid spacing of the most detailed chunk meshes, so that chunk vertices inside the
+    /// rectangle land on samples if its corners lie on vertices too.
+    pub fn with_mesh_resolution(min: Vec2, max: Vec2, chunk_size: f32, divisions: (usize, usize)) -> ExportRegion {
+        let cells = (max - min) / chunk_size * Vec2::new((divisions.0 - 1) as f32, (divisions.1 - 1) as f32);
+        ExportRegion { min, max, resolution: (cells.x.round() as usize + 1, cells.y.round() as usize + 1) }
+    }
+}
+
+/// World space heights of the terrain in `region`, row by row from `min.y` to `max.y`, with the
+/// same mapping as the chunk meshes including erosion. Runs without a window or GL context.
+pub fn export_heights<T: Generator<2>>(tiles: &TileCache<T>, region: &ExportRegion) -> HeightGrid {
+    let (width, height) = region.resolution;
+    let step = (region.max - region.min) / Vec2::new((width - 1).max(1) as f32, (height - 1).max(1) as f32);
+    let heights = tiles.params().heights;
+    HeightGrid::from_fn(width, height, |xi, yi| {
+        heights.apply(tiles.height(region.min.x + xi as f32 * step.x, region.min.y + yi as f32 * step.y))
+    })
+}
+
+/// Writes the heights of `region` to `path`: a 16 bit grayscale PNG spanning the possible height
+/// range of the terrain, or raw f32 world heights for `.f32` and `.r32` files.
+pub fn export_region<T: Generator<2>>(tiles: &TileCache<T>, region: &ExportRegion, path: &str) -> Result<(), String> {
+    let format = RasterFormat::from_path(path).filter(|format| matches!(format, RasterFormat::Png | RasterFormat::RawF32));
+    let format = format.ok_or_else(|| format!("{path}: can only export .png, .f32 or .r32 files"))?;
+    let grid = export_heights(tiles, region);
+    if format == RasterFormat::Png {
+        let heights = tiles.params().heights;
+        save_png16(&grid, path, (heights.apply(0.0), heights.apply(1.0)))
+    } else {
+        save_raw_f32(&grid, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::erosion::{ErosionStage, HydraulicErosion, ThermalErosion};
+    use crate::heightmap::{ChunkMesh, HeightScale, MeshParams};
+    use crate::raster::load_raster;
+
+    fn tiles(erosion: Vec<ErosionStage>) -> TileCache<impl Generator<2>> {
+        let params = MeshParams {
+            chunk_size: 2.0,
+            divisions: (17, 17),
+            terrain_scale: 20.0,
+            heights: HeightScale { scale: 3.0, offset: -1.0 },
+            erosion,
+            seed: 3,
+        };
+        TileCache::new(Arc::new(Source::simplex(3).fbm(4, 0.02, 2.0, 0.5)), params, 64)
+    }
+
+    #[test]
+    fn export_matches_the_chunk_mesh() {
+        let erosion = vec![
+            ErosionStage::Hydraulic(HydraulicErosion { halo: 4, ..HydraulicErosion::default() }),
+            ErosionStage::Thermal(ThermalErosion { iterations: 4, ..ThermalErosion::default() }),
+        ];
+        for tiles in [tiles(Vec::new()), tiles(erosion)] {
+            let params = tiles.params();
+            let key = IVec2::new(1, -2);
+            let mesh = ChunkMesh::new(&tiles, key, 0);
+            let min = key.as_vec2() * params.chunk_size;
+            let region = ExportRegion::with_mesh_resolution(min, min + params.chunk_size, params.chunk_size, params.divisions);
+            assert_eq!(region.resolution, params.divisions);
+            let grid = export_heights(&tiles, &region);
+            let (_, y_divisions) = mesh.divisions;
+            for (index, vertex) in mesh.vertices.iter().enumerate() {
+                let (xi, yi) = (index / y_divisions, index % y_divisions);
+                let height = grid.get(xi, yi);
+                assert!((height - vertex.pos.y).abs() < 1e-5, "({xi}, {yi}): {height} != {}", vertex.pos.y);
+            }
+        }
+    }
+
+    #[test]
+    fn png16_round_trips_through_load_raster() {
+        let grid = HeightGrid { width: 3, height: 2, data: vec![-1.0, 0.0, 0.5, 1.0, 2.0, 0.25] };
+        let path = std::env::temp_dir().join(format!("dirtjam-export-{}.png", std::process::id()));
+        let path = path.to_str().unwrap();
+        save_png16(&grid, path, (0.0, 1.0)).unwrap();
+        let loaded = load_raster(path, RasterFormat::Png, None);
+        std::fs::remove_file(path).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!((loaded.width, loaded.height), (3, 2));
+        // heights outside of the range clamp to black and white
+        let expected = [0.0, 0.0, 32768.0, 65535.0, 65535.0, 16384.0];
+        assert_eq!(loaded.data, expected);
+    }
+}
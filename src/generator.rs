@@ -0,0 +1,415 @@
+use libnoise::prelude::*;
+use toml::{Table, Value};
+
+use crate::raster::{load_raster, Interpolation, Outside, RasterFormat, RasterSource};
+
+/// A type erased `Generator<2>`, so generators built at runtime share one type with the `Heightmap`.
+pub struct BoxedGenerator(Box<dyn Fn([f64; 2]) -> f64 + Send + Sync>);
+
+impl BoxedGenerator {
+    pub fn new<G: Generator<2> + Send + Sync + 'static>(generator: G) -> BoxedGenerator {
+        BoxedGenerator(Box::new(move |point| generator.sample(point)))
+    }
+}
+
+impl Generator<2> for BoxedGenerator {
+    fn sample(&self, point: [f64; 2]) -> f64 {
+        (self.0)(point)
+    }
+}
+
+impl Generator2D for BoxedGenerator {}
+
+/// Parameters of the built-in terrain, fbm over simplex noise.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FbmParams {
+    pub octaves: u32,
+    pub frequency: f64,
+    pub lacunarity: f64,
+    pub persistence: f64,
+}
+
+impl Default for FbmParams {
+    fn default() -> Self {
+        FbmParams { octaves: 5, frequency: 0.013, lacunarity: 2.0, persistence: 0.5 }
+    }
+}
+
+impl FbmParams {
+    pub fn generator(&self, seed: u64) -> BoxedGenerator {
+        BoxedGenerator::new(Source::simplex(seed).fbm(self.octaves, self.frequency, self.lacunarity, self.persistence))
+    }
+}
+
+/// Reads a terrain description file, see `compile_generator`.
+pub fn load_generator(path: &str, seed: u64, terrain_scale: f64) -> Result<BoxedGenerator, String> {
+    let text = std::fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+    let description: Table = text.parse().map_err(|err| format!("{path}: {err}"))?;
+    compile_generator(&description, seed, terrain_scale).map_err(|err| format!("{path}: {err}"))
+}
+
+/// Builds a generator from a description of a libnoise node graph:
+///
+/// ```toml
+/// output = "terrain"
+///
+/// [nodes.terrain]
+/// type = "fbm"
+/// source = { type = "simplex" }
+/// octaves = 5
+/// frequency = 0.013
+/// ```
+///
+/// `output` and every node input are either the name of an entry in `nodes`, an inline node
+/// table or a number for a constant. Noise sources take an optional `seed` that is added to
+/// `seed`. `raster` nodes read real elevation data placed in world units, which the terrain
+/// scale the generator is sampled with converts, see `Compiler::raster`. Errors name the path of
+/// the offending node.
+pub fn compile_generator(description: &Table, seed: u64, terrain_scale: f64) -> Result<BoxedGenerator, String> {
+    let nodes = match description.get("nodes") {
+        None => None,
+        Some(Value::Table(nodes)) => Some(nodes),
+        Some(_) => return Err("nodes: expected a table".to_string()),
+    };
+    let output = description.get("output").ok_or("output: missing")?;
+    let mut compiler = Compiler { nodes, seed, terrain_scale, visiting: Vec::new() };
+    compiler.input(output, "output")
+}
+
+struct Compiler<'a> {
+    nodes: Option<&'a Table>,
+    seed: u64,
+    /// Generator coordinates per world unit, see `terrain_height`.
+    terrain_scale: f64,
+    /// Named nodes currently being compiled, to report cycles.
+    visiting: Vec<&'a str>,
+}
+
+impl<'a> Compiler<'a> {
+    fn input(&mut self, value: &'a Value, path: &str) -> Result<BoxedGenerator, String> {
+        match value {
+            Value::String(name) => {
+                let node = self.nodes.and_then(|nodes| nodes.get(name.as_str()))
+                    .ok_or_else(|| format!("{path}: no node named {name:?}"))?;
+                if self.visiting.contains(&name.as_str()) {
+                    return Err(format!("{path}: node {name:?} depends on itself"));
+                }
+                self.visiting.push(name);
+                let generator = self.input(node, &format!("nodes.{name}"));
+                self.visiting.pop();
+                generator
+            }
+            Value::Table(node) => self.node(node, path),
+            Value::Float(_) | Value::Integer(_) => Ok(BoxedGenerator::new(Source::constant(number(value, path)?))),
+            _ => Err(format!("{path}: expected a node name, a node table or a number")),
+        }
+    }
+
+    fn node(&mut self, node: &'a Table, path: &str) -> Result<BoxedGenerator, String> {
+        let kind = match node.get("type") {
+            Some(Value::String(kind)) => kind.as_str(),
+            Some(_) => return Err(format!("{path}.type: expected a string")),
+            None => return Err(format!("{path}.type: missing")),
+        };
+        let allowed: &[&str] = match kind {
+            "simplex" | "perlin" | "improved_perlin" | "value" | "worley" => &["seed"],
+            "checkerboard" => &[],
+            "constant" => &["value"],
+            "fbm" | "billow" => &["source", "octaves", "frequency", "lacunarity", "persistence"],
+            "ridgedmulti" => &["source", "octaves", "frequency", "lacunarity", "attenuation"],
+            "scale" => &["source", "scale"],
+            "add" | "mul" => &["source", "value", "sources"],
+            "clamp" => &["source", "min", "max"],
+            "select" => &["source_a", "source_b", "control", "min", "max"],
+            "blend" => &["source_a", "source_b", "control"],
+            "terraces" => &["source", "points", "invert"],
+            "raster" => &["path", "format", "width", "origin", "size", "range", "nodata", "interpolation", "outside", "fallback"],
+            _ => return Err(format!("{path}.type: unknown node type {kind:?}")),
+        };
+        if let Some(key) = node.keys().find(|key| *key != "type" && !allowed.contains(&key.as_str())) {
+            return Err(format!("{path}.{key}: unknown parameter for {kind:?}"));
+        }
+
+        let seed = self.seed.wrapping_add_signed(param(node, "seed", path, 0.0)? as i64);
+        Ok(match kind {
+            "simplex" => BoxedGenerator::new(Source::simplex(seed)),
+            "perlin" => BoxedGenerator::new(Source::perlin(seed)),
+            "improved_perlin" => BoxedGenerator::new(Source::improved_perlin(seed)),
+            "value" => BoxedGenerator::new(Source::value(seed)),
+            "worley" => BoxedGenerator::new(Source::worley(seed)),
+            "checkerboard" => BoxedGenerator::new(Source::checkerboard()),
+            "constant" => BoxedGenerator::new(Source::constant(param(node, "value", path, 0.0)?)),
+            "fbm" | "billow" | "ridgedmulti" => {
+                let source = self.source(node, "source", path)?;
+                let octaves = param(node, "octaves", path, 6.0)?;
+                if octaves < 1.0 || octaves.fract() != 0.0 || octaves > u32::MAX as f64 {
+                    return Err(format!("{path}.octaves: expected a whole number of at least 1, not {octaves}"));
+                }
+                let octaves = octaves as u32;
+                let frequency = param(node, "frequency", path, 1.0)?;
+                let lacunarity = param(node, "lacunarity", path, 2.0)?;
+                match kind {
+                    "fbm" => BoxedGenerator::new(source.fbm(octaves, frequency, lacunarity, param(node, "persistence", path, 0.5)?)),
+                    "billow" => BoxedGenerator::new(source.billow(octaves, frequency, lacunarity, param(node, "persistence", path, 0.5)?)),
+                    _ => BoxedGenerator::new(source.ridgedmulti(octaves, frequency, lacunarity, param(node, "attenuation", path, 2.0)?)),
+                }
+            }
+            "scale" => {
+                let source = self.source(node, "source", path)?;
+                let scale = pair(node, "scale", path)?.ok_or_else(|| format!("{path}.scale: missing"))?;
+                BoxedGenerator::new(source.scale(scale))
+            }
+            "add" | "mul" => {
+                let combine = if kind == "add" { |a: f64, b: f64| a + b } else { |a: f64, b: f64| a * b };
+                let mut inputs = Vec::new();
+                if node.contains_key("source") {
+                    inputs.push(self.source(node, "source", path)?);
+                }
+                match node.get("sources") {
+                    Some(Value::Array(sources)) => {
+                        for (index, source) in sources.iter().enumerate() {
+                            inputs.push(self.input(source, &format!("{path}.sources[{index}]"))?);
+                        }
+                    }
+                    Some(_) => return Err(format!("{path}.sources: expected an array")),
+                    None => {}
+                }
+                if node.contains_key("value") {
+                    inputs.push(BoxedGenerator::new(Source::constant(param(node, "value", path, 0.0)?)));
+                }
+                if inputs.len() < 2 {
+                    return Err(format!("{path}: {kind:?} needs at least two of source, sources and value"));
+                }
+                BoxedGenerator(Box::new(move |point| {
+                    inputs.iter().map(|input| input.sample(point)).reduce(combine).unwrap()
+                }))
+            }
+            "clamp" => {
+                let source = self.source(node, "source", path)?;
+                BoxedGenerator::new(source.clamp(param(node, "min", path, -1.0)?, param(node, "max", path, 1.0)?))
+            }
+            "select" => {
+                let (a, b, control) = (self.source(node, "source_a", path)?, self.source(node, "source_b", path)?, self.source(node, "control", path)?);
+                BoxedGenerator::new(a.select(b, control, param(node, "min", path, 0.0)?, param(node, "max", path, 1.0)?))
+            }
+            "blend" => {
+                let (a, b, control) = (self.source(node, "source_a", path)?, self.source(node, "source_b", path)?, self.source(node, "control", path)?);
+                BoxedGenerator::new(a.blend(b, control))
+            }
+            "terraces" => {
+                let source = self.source(node, "source", path)?;
+                let mut points = match node.get("points") {
+                    Some(Value::Array(values)) => values.iter()
+                        .map(|value| number(value, &format!("{path}.points")))
+                        .collect::<Result<Vec<_>, _>>()?,
+                    _ => return Err(format!("{path}.points: expected an array of numbers")),
+                };
+                if points.len() < 2 {
+                    return Err(format!("{path}.points: need at least two points"));
+                }
+                points.sort_by(f64::total_cmp);
+                let invert = match node.get("invert") {
+                    None => false,
+                    Some(Value::Boolean(invert)) => *invert,
+                    Some(_) => return Err(format!("{path}.invert: expected true or false")),
+                };
+                BoxedGenerator(Box::new(move |point| terrace(source.sample(point), &points, invert)))
+            }
+            "raster" => BoxedGenerator::new(self.raster(node, path)?),
+            _ => unreachable!(),
+        })
+    }
+
+    /// A `RasterSource` over a height file:
+    ///
+    /// ```toml
+    /// type = "raster"
+    /// path = "dem.tif"          # png, tif/tiff, f32/r32 or u16/r16/raw
+    /// format = "tiff"           # overrides the extension: "png", "tiff", "f32" or "u16"
+    /// width = 1024              # samples per row, only for raw files
+    /// origin = [0.0, 0.0]       # world position of the first sample's corner
+    /// size = [1024.0, 1024.0]   # covered world area, one unit per sample by default
+    /// range = [0.0, 4000.0]     # samples mapped to -1 and 1, the lowest and highest by default
+    /// nodata = -9999.0          # missing samples, GeoTIFFs may already name theirs
+    /// interpolation = "bicubic" # or "bilinear"
+    /// outside = "fallback"      # "clamp", "wrap" or "fallback" to the `fallback` input
+    /// fallback = "hills"
+    /// ```
+    ///
+    /// `origin` and `size` stay in world units as long as no `scale` node above the raster
+    /// changes the coordinates it is sampled at.
+    fn raster(&mut self, node: &'a Table, path: &str) -> Result<RasterSource, String> {
+        let string = |key: &str| match node.get(key) {
+            None => Ok(None),
+            Some(Value::String(value)) => Ok(Some(value.as_str())),
+            Some(_) => Err(format!("{path}.{key}: expected a string")),
+        };
+        let file = string("path")?.ok_or_else(|| format!("{path}.path: missing"))?;
+        let format = match string("format")? {
+            Some(name) => RasterFormat::from_name(name).ok_or_else(|| format!("{path}.format: unknown format {name:?}"))?,
+            None => RasterFormat::from_path(file).ok_or_else(|| format!("{path}.format: cannot tell the format of {file:?}"))?,
+        };
+        let width = match node.get("width") {
+            None => None,
+            Some(Value::Integer(width)) if *width > 0 => Some(*width as usize),
+            Some(_) => return Err(format!("{path}.width: expected a positive integer")),
+        };
+        let mut grid = load_raster(file, format, width).map_err(|err| format!("{path}: {err}"))?;
+        if let Some(nodata) = node.get("nodata") {
+            let nodata = number(nodata, &format!("{path}.nodata"))? as f32;
+            grid.data.iter_mut().filter(|value| **value == nodata).for_each(|value| *value = f32::NAN);
+        }
+        let mut raster = RasterSource::new(grid).map_err(|err| format!("{path}: {file}: {err}"))?;
+        let origin = pair(node, "origin", path)?.unwrap_or([0.0, 0.0]);
+        let size = pair(node, "size", path)?.unwrap_or(raster.size);
+        if size[0] <= 0.0 || size[1] <= 0.0 {
+            return Err(format!("{path}.size: must be positive"));
+        }
+        raster.origin = origin.map(|coordinate| coordinate * self.terrain_scale);
+        raster.size = size.map(|length| length * self.terrain_scale);
+        if let Some([low, high]) = pair(node, "range", path)? {
+            raster.range = (low as f32, high as f32);
+        }
+        raster.interpolation = match string("interpolation")? {
+            None | Some("bilinear") => Interpolation::Bilinear,
+            Some("bicubic") => Interpolation::Bicubic,
+            Some(name) => return Err(format!("{path}.interpolation: expected \"bilinear\" or \"bicubic\", not {name:?}")),
+        };
+        raster.outside = match string("outside")? {
+            None | Some("clamp") => Outside::Clamp,
+            Some("wrap") => Outside::Wrap,
+            Some("fallback") => Outside::Fallback(self.source(node, "fallback", path)?),
+            Some(name) => return Err(format!("{path}.outside: expected \"clamp\", \"wrap\" or \"fallback\", not {name:?}")),
+        };
+        if node.contains_key("fallback") && !matches!(raster.outside, Outside::Fallback(_)) {
+            return Err(format!("{path}.fallback: only used with outside = \"fallback\""));
+        }
+        Ok(raster)
+    }
+
+    fn source(&mut self, node: &'a Table, key: &str, path: &str) -> Result<BoxedGenerator, String> {
+        let value = node.get(key).ok_or_else(|| format!("{path}.{key}: missing"))?;
+        self.input(value, &format!("{path}.{key}"))
+    }
+}
+
+fn number(value: &Value, path: &str) -> Result<f64, String> {
+    match value {
+        Value::Float(v) => Ok(*v),
+        Value::Integer(v) => Ok(*v as f64),
+        _ => Err(format!("{path}: expected a number")),
+    }
+}
+
+/// Either `[x, y]` or one number for both.
+fn pair(node: &Table, key: &str, path: &str) -> Result<Option<[f64; 2]>, String> {
+    let path = format!("{path}.{key}");
+    match node.get(key) {
+        None => Ok(None),
+        Some(Value::Array(values)) if values.len() == 2 => Ok(Some([number(&values[0], &path)?, number(&values[1], &path)?])),
+        Some(value) => Ok(Some([number(value, &path)?; 2])),
+    }
+}
+
+fn param(node: &Table, key: &str, path: &str, default: f64) -> Result<f64, String> {
+    node.get(key).map_or(Ok(default), |value| number(value, &format!("{path}.{key}")))
+}
+
+/// Like libnoise's terrace module: flattens the value towards the control point below it, with
+/// a steep rise just before the next one.
+fn terrace(value: f64, points: &[f64], invert: bool) -> f64 {
+    let index = points.partition_point(|point| *point <= value).clamp(1, points.len() - 1);
+    let (low, high) = (points[index - 1], points[index]);
+    if value <= low {
+        return low;
+    }
+    if value >= high {
+        return high;
+    }
+    let mut t = (value - low) / (high - low);
+    let (mut low, mut high) = (low, high);
+    if invert {
+        t = 1.0 - t;
+        std::mem::swap(&mut low, &mut high);
+    }
+    low + (high - low) * t * t
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heightmap::terrain_height;
+
+    /// The error of compiling the TOML `description`.
+    fn compile_error(description: &str) -> String {
+        let description: Table = description.parse().unwrap();
+        compile_generator(&description, 0, 1.0).err().expect("compiled an invalid description")
+    }
+
+    #[test]
+    fn compiles_a_node_graph() {
+        let description: Table = r#"
+            output = "terrain"
+            [nodes.terrain]
+            type = "add"
+            sources = ["hills", 0.5]
+            [nodes.hills]
+            type = "fbm"
+            source = { type = "simplex", seed = 1 }
+            octaves = 3
+        "#.parse().unwrap();
+        let generator = compile_generator(&description, 7, 1.0).unwrap();
+        let expected = Source::simplex(8).fbm(3, 1.0, 2.0, 0.5);
+        for point in [[0.3, 0.7], [12.5, -4.0]] {
+            assert_eq!(generator.sample(point), expected.sample(point) + 0.5);
+        }
+    }
+
+    #[test]
+    fn errors_name_the_offending_node() {
+        assert_eq!(compile_error(r#"output = { type = "fractal" }"#), r#"output.type: unknown node type "fractal""#);
+        assert_eq!(
+            compile_error(r#"output = { type = "simplex", frequency = 2.0 }"#),
+            r#"output.frequency: unknown parameter for "simplex""#,
+        );
+        assert_eq!(compile_error(r#"output = { type = "fbm" }"#), "output.source: missing");
+        assert_eq!(compile_error(r#"output = "hills""#), r#"output: no node named "hills""#);
+        let cycle = r#"
+            output = "a"
+            [nodes.a]
+            type = "scale"
+            source = "b"
+            scale = [2.0, 2.0]
+            [nodes.b]
+            type = "clamp"
+            source = "a"
+        "#;
+        assert_eq!(compile_error(cycle), r#"nodes.b.source: node "a" depends on itself"#);
+    }
+
+    #[test]
+    fn octaves_must_be_whole_and_positive() {
+        for octaves in ["0", "-2", "2.5"] {
+            let error = compile_error(&format!("output = {{ type = \"billow\", source = {{ type = \"simplex\" }}, octaves = {octaves} }}"));
+            assert!(error.starts_with("output.octaves: "), "{error}");
+        }
+    }
+
+    #[test]
+    fn raster_extent_is_in_world_units() {
+        let file = std::env::temp_dir().join(format!("dirtjam-raster-{}.f32", std::process::id()));
+        let data: Vec<u8> = [0.0f32, 10.0].iter().flat_map(|value| value.to_le_bytes()).collect();
+        std::fs::write(&file, data).unwrap();
+        let description: Table = format!(
+            "[output]\ntype = \"raster\"\npath = {:?}\nwidth = 2\norigin = [10.0, 0.0]\nsize = [4.0, 2.0]\n",
+            file.to_str().unwrap(),
+        ).parse().unwrap();
+        let generator = compile_generator(&description, 0, 0.5);
+        std::fs::remove_file(&file).unwrap();
+        let generator = generator.unwrap();
+        // samples are centered on their two world units wide cells
+        assert_eq!(terrain_height(&generator, 11.0, 1.0, 0.5), 0.0);
+        assert_eq!(terrain_height(&generator, 12.0, 1.0, 0.5), 0.5);
+        assert_eq!(terrain_height(&generator, 13.0, 1.0, 0.5), 1.0);
+    }
+}
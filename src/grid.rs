@@ -0,0 +1,62 @@
+use macroquad::prelude::*;
+
+/// Heights on a regular grid with a spacing of one unit, stored row by row.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeightGrid {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<f32>,
+}
+
+impl HeightGrid {
+    pub fn new(width: usize, height: usize) -> HeightGrid {
+        HeightGrid { width, height, data: vec![0.0; width * height] }
+    }
+
+    /// Fills the grid with `f(x, y)`, row by row.
+    pub fn from_fn(width: usize, height: usize, mut f: impl FnMut(usize, usize) -> f32) -> HeightGrid {
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                data.push(f(x, y));
+            }
+        }
+        HeightGrid { width, height, data }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> f32 {
+        self.data[y * self.width + x]
+    }
+
+    pub fn get_mut(&mut self, x: usize, y: usize) -> &mut f32 {
+        &mut self.data[y * self.width + x]
+    }
+
+    /// Bilinear interpolation between the grid points, exact at the points themselves. Positions
+    /// outside the grid are clamped onto its border.
+    pub fn sample(&self, x: f32, y: f32) -> f32 {
+        let x = x.clamp(0.0, (self.width - 1) as f32);
+        let y = y.clamp(0.0, (self.height - 1) as f32);
+        let (xi, yi) = (x as usize, y as usize);
+        let (s, t) = (x - xi as f32, y - yi as f32);
+        let (next_x, next_y) = ((xi + 1).min(self.width - 1), (yi + 1).min(self.height - 1));
+        let top = self.get(xi, yi).lerp(self.get(next_x, yi), s);
+        let bottom = self.get(xi, next_y).lerp(self.get(next_x, next_y), s);
+        top.lerp(bottom, t)
+    }
+
+    /// Interpolated height and its gradient inside the cell containing `(x, y)`. The position
+    /// must be at least one unit away from the right and bottom border.
+    pub fn height_and_gradient(&self, x: f32, y: f32) -> (f32, Vec2) {
+        let (xi, yi) = (x as usize, y as usize);
+        let (s, t) = (x - xi as f32, y - yi as f32);
+        let (h00, h10) = (self.get(xi, yi), self.get(xi + 1, yi));
+        let (h01, h11) = (self.get(xi, yi + 1), self.get(xi + 1, yi + 1));
+        let gradient = Vec2::new(
+            (h10 - h00) * (1.0 - t) + (h11 - h01) * t,
+            (h01 - h00) * (1.0 - s) + (h11 - h10) * s,
+        );
+        let height = h00 * (1.0 - s) * (1.0 - t) + h10 * s * (1.0 - t) + h01 * (1.0 - s) * t + h11 * s * t;
+        (height, gradient)
+    }
+}
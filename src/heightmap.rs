@@ -2,18 +2,112 @@ use macroquad::miniquad::*;
 use macroquad::prelude::*;
 use libnoise::prelude::*;
 use std::collections::HashMap;
+use std::sync::Arc;
 
+use crate::camera::Frustum;
+use crate::erosion::ErosionStage;
+use crate::tiles::TileCache;
+use crate::worker::{ChunkJob, ChunkWorkers};
+
+/// Maps a generator sample at world position `(x, y)` into the `[0, 1]` height range used by the mesh.
+pub fn terrain_height<T: Generator<2>>(generator: &T, x: f32, y: f32, terrain_scale: f64) -> f32 {
+    0.5 * (generator.sample([x as f64 * terrain_scale, y as f64 * terrain_scale]) as f32 + 1.0)
+}
+
+/// Whether `divisions` vertices per side can be halved down the LODs with every coarse vertex
+/// landing on a vertex of the most detailed LOD, which stitching relies on: `2^k + 1`.
+pub fn valid_divisions(divisions: usize) -> bool {
+    divisions >= 2 && (divisions - 1).is_power_of_two()
+}
+
+/// Vertex grid size of a chunk at `lod` when the most detailed LOD has `divisions`.
+pub fn lod_divisions(divisions: (usize, usize), lod: usize) -> (usize, usize) {
+    let (x_divisions, y_divisions) = divisions;
+    (((x_divisions - 1) >> lod).max(1) + 1, ((y_divisions - 1) >> lod).max(1) + 1)
+}
+
+/// How many vertices of an edge with `divisions` vertices map onto one step of the neighbouring
+/// edge with `neighbour_divisions`, the stitch of `ChunkMesh::grid_indices`. Compares the real
+/// grids because LODs stop shrinking at 2 vertices per side.
+pub fn edge_stitch(divisions: usize, neighbour_divisions: usize) -> usize {
+    ((divisions - 1) / (neighbour_divisions - 1)).max(1)
+}
+
+/// Height at `(s, t)` in `[0, 1]^2` on a grid cell with corner heights `h00`, `h10`, `h01` and
+/// `h11` (x then y), interpolated on the same two triangles as `ChunkMesh::grid_indices`, which
+/// split the cell along the diagonal from `h00` to `h11`.
+pub fn cell_height([h00, h10, h01, h11]: [f32; 4], s: f32, t: f32) -> f32 {
+    if s >= t {
+        h00 + s * (h10 - h00) + t * (h11 - h10)
+    } else {
+        h00 + t * (h01 - h00) + s * (h11 - h01)
+    }
+}
+
+/// Maps the `[0, 1]` range of `terrain_height` to world space heights.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HeightScale {
+    /// World units between the lowest and the highest possible point.
+    pub scale: f32,
+    /// World space height of the lowest possible point.
+    pub offset: f32,
+}
+
+impl Default for HeightScale {
+    fn default() -> Self {
+        HeightScale { scale: 1.0, offset: 0.0 }
+    }
+}
+
+impl HeightScale {
+    pub fn apply(&self, height: f32) -> f32 {
+        self.offset + self.scale * height
+    }
+}
+
+/// Everything besides the generator and the LOD that goes into building a chunk mesh.
+#[derive(Clone, Debug)]
+pub struct MeshParams {
+    pub chunk_size: f32,
+    /// Divisions of the most detailed LOD, the resolution erosion runs at.
+    pub divisions: (usize, usize),
+    pub terrain_scale: f64,
+    pub heights: HeightScale,
+    pub erosion: Vec<ErosionStage>,
+    /// Seeds the random choices of the erosion stages.
+    pub seed: u64,
+}
+
+/// CPU side geometry of a chunk. Building it does not touch the GPU, so it can be generated
+/// off the main thread and inspected without a GL context.
 #[derive(Clone)]
-pub struct Chunk {
-    offset: Vec2,
-    bindings: Bindings,
-    indices_len: i32,
+pub struct ChunkMesh {
+    pub offset: Vec2,
+    pub divisions: (usize, usize),
+    pub vertices: Vec<Vertex>,
+    pub indices: Vec<u32>,
 }
 
-impl Chunk {
-    pub fn new<T: Generator<2>>(generator: &T, offset: Vec2, texture_ids: Vec<TextureId>, divisions: (usize, usize), terrain_scale: f64) -> Chunk {
-        let ctx = Box::new(unsafe { macroquad::window::get_internal_gl().quad_context });
+impl ChunkMesh {
+    /// Builds chunk `key` at `lod` from the heights in `tiles`. Normals along the border use the
+    /// neighbouring tiles.
+    pub fn new<T: Generator<2>>(tiles: &TileCache<T>, key: IVec2, lod: usize) -> ChunkMesh {
+        let params = tiles.params();
+        let (size, heights) = (params.chunk_size, params.heights);
+        let offset = key.as_vec2() * size;
+        let divisions = lod_divisions(params.divisions, lod);
         let (x_divisions, y_divisions) = divisions;
+        let tile = tiles.heights(key, lod);
+        let [prev_x_tile, next_x_tile, prev_y_tile, next_y_tile] = [IVec2::NEG_X, IVec2::X, IVec2::NEG_Y, IVec2::Y]
+            .map(|direction| tiles.heights(key + direction, lod));
+        // tiles share their border vertices, so one step over the border is the second vertex of the next tile
+        let height = |xi: isize, yi: isize| match (xi, yi) {
+            (-1, _) => prev_x_tile.get(x_divisions - 2, yi as usize),
+            (_, -1) => prev_y_tile.get(xi as usize, y_divisions - 2),
+            _ if xi as usize == x_divisions => next_x_tile.get(1, yi as usize),
+            _ if yi as usize == y_divisions => next_y_tile.get(xi as usize, 1),
+            _ => tile.get(xi as usize, yi as usize),
+        };
         let mut vertices = Vec::with_capacity(x_divisions * y_divisions);
         for xi in 0..x_divisions {
             for yi in 0..y_divisions {
@@ -27,21 +121,23 @@ impl Chunk {
                 );
                 //let (x, y) = (2. * u - 1., 2. * v - 1.);
                 let (x, y, next_x, next_y, prev_x, prev_y) = (
-                    u+offset.x,
-                    v+offset.y,
-                    next_u+offset.x,
-                    next_v+offset.y,
-                    prev_u+offset.x,
-                    prev_v+offset.y,                   
+                    u*size+offset.x,
+                    v*size+offset.y,
+                    next_u*size+offset.x,
+                    next_v*size+offset.y,
+                    prev_u*size+offset.x,
+                    prev_v*size+offset.y,
                 );
+                let (xs, ys) = (xi as isize, yi as isize);
                 let (height, next_x_height, next_y_height, prev_x_height, prev_y_height) = (
-                0.5 * (generator.sample([x as f64 * terrain_scale, y as f64 * terrain_scale]) as f32 + 1.0),
-                0.5 * (generator.sample([next_x as f64 * terrain_scale, y as f64 * terrain_scale]) as f32 + 1.0),
-                0.5 * (generator.sample([x as f64 * terrain_scale, next_y as f64 * terrain_scale]) as f32 + 1.0),
-                0.5 * (generator.sample([prev_x as f64 * terrain_scale, y as f64 * terrain_scale]) as f32 + 1.0),
-                0.5 * (generator.sample([x as f64 * terrain_scale, prev_y as f64 * terrain_scale]) as f32 + 1.0));
+                    height(xs, ys),
+                    heights.apply(height(xs + 1, ys)),
+                    heights.apply(height(xs, ys + 1)),
+                    heights.apply(height(xs - 1, ys)),
+                    heights.apply(height(xs, ys - 1)),
+                );
                 let (pos, next_x_pos, next_y_pos, prev_x_pos, prev_y_pos) = (
-                    Vec3::new(x, height, y),
+                    Vec3::new(x, heights.apply(height), y),
                     Vec3::new(next_x, next_x_height, y),
                     Vec3::new(x, next_y_height, next_y),
                     Vec3::new(prev_x, prev_x_height, y),
@@ -51,60 +147,223 @@ impl Chunk {
                 let normal2 = (prev_x_pos-pos).cross(prev_y_pos-pos).normalize();
                 let normal = (normal1 + normal2).normalize();
                 vertices.push(Vertex {
-                    pos: Vec3::new(x, height, y),
-                    uv: Vec2::new(u, v),
+                    pos,
+                    // the ground textures repeat once per world unit
+                    uv: Vec2::new(u*size, v*size),
                     color: Vec4::new(1.0, height, height, 1.0),
-                    normal: normal,
+                    normal,
                 });
             }
         }
 
+        let indices = ChunkMesh::grid_indices(divisions, [1; 4]);
+        ChunkMesh { offset, divisions, vertices, indices }
+    }
+
+    /// Axis aligned bounding box of all vertices.
+    pub fn bounds(&self) -> (Vec3, Vec3) {
+        self.vertices.iter().fold((Vec3::INFINITY, Vec3::NEG_INFINITY), |(min, max), vertex| {
+            (min.min(vertex.pos), max.max(vertex.pos))
+        })
+    }
+
+    /// Triangulates the vertex grid. `stitch` holds, for the `-x`, `+x`, `-y` and `+y` edges, how
+    /// many quads of this chunk span one quad of the neighbour. Vertices in between are snapped
+    /// onto the neighbour's coarser edge so the shared border has no T-junctions.
+    pub fn grid_indices(divisions: (usize, usize), stitch: [usize; 4]) -> Vec<u32> {
+        let (x_divisions, y_divisions) = divisions;
+        let vertex_index = |xi: usize, yi: usize| -> u32 {
+            let (mut xi, mut yi) = (xi, yi);
+            if xi == 0 { yi -= yi % stitch[0]; }
+            if xi == x_divisions - 1 { yi -= yi % stitch[1]; }
+            if yi == 0 { xi -= xi % stitch[2]; }
+            if yi == y_divisions - 1 { xi -= xi % stitch[3]; }
+            (xi * y_divisions + yi).try_into().unwrap()
+        };
         let mut indices: Vec<u32> = Vec::with_capacity(6 * x_divisions * y_divisions);
         for xi in 0..x_divisions - 1 {
             for yi in 0..y_divisions - 1 {
-                let index: u32 = (xi * x_divisions + yi).try_into().unwrap();
-                let next_x_index: u32 = ((xi + 1) * x_divisions + yi).try_into().unwrap();
-                let next_y_index: u32 = (xi * x_divisions + yi + 1).try_into().unwrap();
-                let next_xy_index: u32 = ((xi + 1) * x_divisions + yi + 1).try_into().unwrap();
+                let index = vertex_index(xi, yi);
+                let next_x_index = vertex_index(xi + 1, yi);
+                let next_y_index = vertex_index(xi, yi + 1);
+                let next_xy_index = vertex_index(xi + 1, yi + 1);
                 indices.extend([index, next_x_index, next_xy_index].iter());
                 indices.extend([index, next_xy_index, next_y_index].iter());
             }
         }
+        indices
+    }
+
+    /// Hangs a vertical strip of `depth` below every border edge, hiding cracks to neighbours of
+    /// any resolution.
+    pub fn add_skirts(&mut self, depth: f32) {
+        let (x_divisions, y_divisions) = self.divisions;
+        let edges: [Vec<usize>; 4] = [
+            (0..y_divisions).collect(),
+            (0..y_divisions).map(|yi| (x_divisions - 1) * y_divisions + yi).collect(),
+            (0..x_divisions).map(|xi| xi * y_divisions).collect(),
+            (0..x_divisions).map(|xi| xi * y_divisions + y_divisions - 1).collect(),
+        ];
+        for edge in edges {
+            let first_skirt_index = self.vertices.len();
+            for &index in &edge {
+                let mut vertex = self.vertices[index];
+                vertex.pos.y -= depth;
+                self.vertices.push(vertex);
+            }
+            for i in 0..edge.len() - 1 {
+                let (top, next_top) = (edge[i] as u32, edge[i + 1] as u32);
+                let (bottom, next_bottom) = ((first_skirt_index + i) as u32, (first_skirt_index + i + 1) as u32);
+                self.indices.extend([top, next_top, next_bottom]);
+                self.indices.extend([top, next_bottom, bottom]);
+            }
+        }
+    }
+}
+
+/// Marches along a normalized ray over the heightfield `height(x, z)` in increments of `step` and
+/// refines the first crossing below the surface by bisection. Returns the distance to the hit.
+pub fn march_ray(height: impl Fn(f32, f32) -> f32, origin: Vec3, dir: Vec3, max_dist: f32, step: f32) -> Option<f32> {
+    let above = |t: f32| {
+        let point = origin + t * dir;
+        point.y - height(point.x, point.z)
+    };
+    if above(0.) < 0. {
+        return None;
+    }
+    let mut t = 0.;
+    while t < max_dist {
+        let next = (t + step).min(max_dist);
+        if above(next) < 0. {
+            let (mut low, mut high) = (t, next);
+            for _ in 0..16 {
+                let mid = 0.5 * (low + high);
+                if above(mid) < 0. { high = mid; } else { low = mid; }
+            }
+            return Some(high);
+        }
+        t = next;
+    }
+    None
+}
+
+/// Where a ray hit the terrain.
+#[derive(Clone, Copy, Debug)]
+pub struct Hit {
+    pub position: Vec3,
+    pub normal: Vec3,
+    pub distance: f32,
+    pub chunk: IVec2,
+}
+
+/// How `Heightmap::height_at` and friends evaluate the terrain.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeightSampling {
+    /// Sample the generator directly at the query position.
+    Exact,
+    /// Interpolate linearly on the triangles of the resident chunk's grid, so results follow
+    /// the tessellated surface that is drawn.
+    Bilinear,
+}
+
+/// Controls which chunks are built and how long they are kept around.
+#[derive(Clone, Copy, Debug)]
+pub struct StreamingConfig {
+    /// Chunks whose center is within this distance of the camera are built.
+    pub load_radius: f32,
+    /// Chunks further away than this are dropped. Should be larger than `load_radius`.
+    pub unload_radius: f32,
+    /// Upper bound on resident chunks. When exceeded, the chunks that have not been visible for
+    /// the longest time are evicted, but never one that is in view or within `load_radius`,
+    /// which would only be built again. Small chunks may need more than this.
+    pub max_resident: usize,
+    /// How many finished chunk meshes are uploaded to the GPU per frame.
+    pub build_budget: usize,
+    /// Height tiles kept in the `TileCache`. Every chunk needs a few, more with erosion.
+    pub cached_tiles: usize,
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        StreamingConfig { load_radius: 10.0, unload_radius: 14.0, max_resident: 600, build_budget: 4, cached_tiles: 4096 }
+    }
+}
+
+/// How cracks between neighbouring chunks of different LOD are closed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SeamMode {
+    /// Leave the seams open.
+    None,
+    /// Vertical skirts of the given depth below every chunk border.
+    Skirts(f32),
+    /// Rebuild the index buffer of the finer chunk whenever a neighbour's LOD changes.
+    Stitch,
+}
+
+#[derive(Clone)]
+pub struct Chunk {
+    pub lod: usize,
+    /// `Heightmap::generation` of the generator this chunk was built from.
+    pub generation: u64,
+    /// Frame in which the chunk last passed frustum culling.
+    pub last_visible: u64,
+    /// World space bounding box of the uploaded mesh.
+    pub min: Vec3,
+    pub max: Vec3,
+    divisions: (usize, usize),
+    stitch: [usize; 4],
+    bindings: Bindings,
+    indices_len: i32,
+}
+
+impl Chunk {
+    /// Uploads a finished mesh to the GPU.
+    pub fn new(mesh: &ChunkMesh, lod: usize, texture_ids: Vec<TextureId>) -> Chunk {
+        let ctx = unsafe { macroquad::window::get_internal_gl().quad_context };
 
         let vertex_buffer = ctx.new_buffer(
             BufferType::VertexBuffer,
             BufferUsage::Immutable,
-            BufferSource::slice(&vertices),
+            BufferSource::slice(&mesh.vertices),
         );
 
 
         let index_buffer = ctx.new_buffer(
             BufferType::IndexBuffer,
             BufferUsage::Immutable,
-            BufferSource::slice(&indices),
+            BufferSource::slice(&mesh.indices),
         );
 
         let bindings = Bindings {
             vertex_buffers: vec![vertex_buffer],
-            index_buffer: index_buffer,
+            index_buffer,
             images: texture_ids,
         };
-        Chunk {offset: offset, bindings: bindings, indices_len: indices.len() as i32}
+        let (min, max) = mesh.bounds();
+        Chunk {lod, generation: 0, last_visible: 0, min, max, divisions: mesh.divisions, stitch: [1; 4], bindings, indices_len: mesh.indices.len() as i32}
     }
 
-    fn draw(&mut self, pipeline: &Pipeline, camera: &Camera3D, light_dir: Vec3) {
-        let ctx = Box::new(unsafe { macroquad::window::get_internal_gl().quad_context });
+    /// Replaces the index buffer with one stitched to neighbours as described in `ChunkMesh::grid_indices`.
+    pub fn restitch(&mut self, stitch: [usize; 4]) {
+        if stitch == self.stitch {
+            return;
+        }
+        let ctx = unsafe { macroquad::window::get_internal_gl().quad_context };
+        let indices = ChunkMesh::grid_indices(self.divisions, stitch);
+        ctx.delete_buffer(self.bindings.index_buffer);
+        self.bindings.index_buffer = ctx.new_buffer(
+            BufferType::IndexBuffer,
+            BufferUsage::Immutable,
+            BufferSource::slice(&indices),
+        );
+        self.indices_len = indices.len() as i32;
+        self.stitch = stitch;
+    }
 
-        ctx.apply_pipeline(&pipeline);
+    /// Expects the pipeline and uniforms to be applied already.
+    fn draw(&self, ctx: &mut dyn RenderingBackend) {
         ctx.apply_bindings(&self.bindings);
-
-        ctx.apply_uniforms(UniformsSource::table(&shader::Uniforms {
-            projection: camera.matrix(),
-            model: Mat4::IDENTITY,
-            light_dir: -light_dir.normalize(),
-        }));
         ctx.draw(0, self.indices_len, 1);
-        ctx.end_render_pass();
     }
 }
 
@@ -120,25 +379,60 @@ impl Drop for Chunk {
 
 
 #[repr(C)]
-struct Vertex {
-    pos: Vec3,
-    uv: Vec2,
-    color: Vec4,
-    normal: Vec3,
+#[derive(Clone, Copy, Debug)]
+pub struct Vertex {
+    pub pos: Vec3,
+    pub uv: Vec2,
+    pub color: Vec4,
+    /// Points into the ground, `draw` flips the light direction to match.
+    pub normal: Vec3,
 }
 
-pub struct Heightmap<T: Generator<2>> {
+pub struct Heightmap<T: Generator<2> + Send + Sync + 'static> {
     pub pipeline: Pipeline,
-    pub generator: T,
+    pub generator: Arc<T>,
+    pub workers: ChunkWorkers<T>,
     pub textures: Vec<TextureId>,
     pub chunks: HashMap<IVec2, Chunk>,
+    /// Divisions of the most detailed LOD. Every further level halves the number of quads per
+    /// side, so both have to pass `valid_divisions`.
     pub divisions: (usize, usize),
+    /// Converts world coordinates into generator coordinates.
     pub terrain_scale: f64,
+    pub heights: HeightScale,
+    /// Side length of a chunk in world units. Chunk `key` covers `key * chunk_size` to
+    /// `(key + 1) * chunk_size`.
+    pub chunk_size: f32,
+    /// Applied to the sampled heights in order. Chunks of every LOD are then built from eroded
+    /// tiles at the most detailed LOD's resolution, which makes building them slower.
+    pub erosion: Vec<ErosionStage>,
+    /// Seeds the random choices of the erosion stages.
+    pub seed: u64,
+    /// Distances from the camera at which chunks switch to the next coarser LOD.
+    pub lod_distances: Vec<f32>,
+    /// How far past a LOD distance the camera has to move before a resident chunk switches level.
+    pub lod_hysteresis: f32,
+    pub seams: SeamMode,
+    pub sampling: HeightSampling,
+    pub streaming: StreamingConfig,
+    /// Heights at which the ground texture blends from dirt to grass, grass to rock and rock to snow.
+    pub bands: Vec3,
+    pub stats: DrawStats,
+    /// Counts generator replacements. Chunks from an older generation are rebuilt.
+    pub generation: u64,
+    frame: u64,
+}
+
+/// Chunk counts of the last `Heightmap::draw`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DrawStats {
+    pub drawn: usize,
+    pub culled: usize,
 }
 
 
-impl<T: Generator<2>> Heightmap<T> {
-    pub fn new(generator: T, divisions: (usize, usize), terrain_scale: f64) -> Heightmap<T> {
+impl<T: Generator<2> + Send + Sync + 'static> Heightmap<T> {
+    pub fn new(generator: T, chunk_size: f32, divisions: (usize, usize), terrain_scale: f64, heights: HeightScale, streaming: StreamingConfig) -> Heightmap<T> {
         let ctx = Box::new(unsafe { macroquad::window::get_internal_gl().quad_context });
 
         let shader = ctx
@@ -168,39 +462,250 @@ impl<T: Generator<2>> Heightmap<T> {
             },
         );
         let textures = load_textures();
+        let generator = Arc::new(generator);
         Heightmap {
             pipeline,
+            workers: ChunkWorkers::new(generator.clone(), MeshParams {
+                chunk_size, divisions, terrain_scale, heights, erosion: Vec::new(), seed: 0,
+            }, streaming.cached_tiles),
             generator,
             textures,
             chunks: HashMap::new(),
             divisions,
-            terrain_scale
+            terrain_scale,
+            heights,
+            chunk_size,
+            erosion: Vec::new(),
+            seed: 0,
+            lod_distances: vec![3.0, 6.0, 9.0],
+            lod_hysteresis: 0.5,
+            seams: SeamMode::Stitch,
+            sampling: HeightSampling::Exact,
+            streaming,
+            bands: Vec3::new(0.25, 0.5, 0.75),
+            stats: DrawStats::default(),
+            generation: 0,
+            frame: 0,
+        }
+    }
+
+    /// Switches to a new generator and drops all chunks built from the old one.
+    pub fn set_generator(&mut self, generator: T) {
+        self.swap_generator(generator);
+        self.chunks.clear();
+    }
+
+    /// Switches to a new generator but keeps drawing the old chunks until their replacements,
+    /// built nearest to the camera first, are ready.
+    pub fn swap_generator(&mut self, generator: T) {
+        self.generator = Arc::new(generator);
+        self.invalidate();
+    }
+
+    /// Marks all chunks as outdated, e.g. after changing `terrain_scale`, `heights`, `divisions`,
+    /// `erosion` or `seed`. They are rebuilt like after `swap_generator`.
+    pub fn invalidate(&mut self) {
+        self.workers = ChunkWorkers::new(self.generator.clone(), self.mesh_params(), self.streaming.cached_tiles);
+        self.generation += 1;
+    }
+
+    /// Changes the chunk size. All chunks are dropped since their keys now mean different places.
+    pub fn set_chunk_size(&mut self, chunk_size: f32) {
+        self.chunk_size = chunk_size;
+        self.chunks.clear();
+        self.invalidate();
+    }
+
+    pub fn mesh_params(&self) -> MeshParams {
+        MeshParams {
+            chunk_size: self.chunk_size,
+            divisions: self.divisions,
+            terrain_scale: self.terrain_scale,
+            heights: self.heights,
+            erosion: self.erosion.clone(),
+            seed: self.seed,
+        }
+    }
+
+    /// Key of the chunk containing world position `(x, z)`.
+    pub fn chunk_key(&self, x: f32, z: f32) -> IVec2 {
+        IVec2::new((x / self.chunk_size).floor() as i32, (z / self.chunk_size).floor() as i32)
+    }
+
+    /// World space center of chunk `key` in the horizontal plane.
+    pub fn chunk_center(&self, key: IVec2) -> Vec2 {
+        (key.as_vec2() + 0.5) * self.chunk_size
+    }
+
+    /// Vertex grid size of a chunk at the given LOD.
+    pub fn lod_divisions(&self, lod: usize) -> (usize, usize) {
+        lod_divisions(self.divisions, lod)
+    }
+
+    /// Picks the LOD for a chunk at `distance` from the camera. A resident chunk keeps its
+    /// `current` level while it is within `lod_hysteresis` of the boundary.
+    pub fn select_lod(&self, distance: f32, current: Option<usize>) -> usize {
+        let level = |distance: f32| self.lod_distances.iter().filter(|&&d| distance > d).count();
+        let target = level(distance);
+        match current {
+            Some(current) if (level(distance - self.lod_hysteresis)..=level(distance + self.lod_hysteresis)).contains(&current) => current,
+            _ => target,
         }
     }
 
     pub fn draw(&mut self, camera: &Camera3D, light_dir: Vec3) {
         // generate chunks around camera position
-        let camera_offset = camera.position.floor();
-        let camera_offset = IVec2::new(camera_offset.x as i32, camera_offset.z as i32);
-        let mut added = 0;
-        for x in -10..10 {
-            for y in -10..10 {
-                if added > 1 { break; }
-                let offset = camera_offset+IVec2::new(x,y);
-                self.chunks.entry(offset).or_insert_with(|| {
-                    added += 1;
-                    Chunk::new(&self.generator, Vec2::new(offset.x as f32, offset.y as f32), self.textures.clone(), self.divisions, self.terrain_scale)
+        let camera_offset = self.chunk_key(camera.position.x, camera.position.z);
+        let mut jobs = Vec::new();
+        let radius = (self.streaming.load_radius / self.chunk_size).ceil() as i32;
+        for x in -radius..=radius {
+            for y in -radius..=radius {
+                let key = camera_offset+IVec2::new(x,y);
+                let center = self.chunk_center(key);
+                let distance = center.distance(camera.position.xz());
+                if distance > self.streaming.load_radius {
+                    continue;
+                }
+                let chunk = self.chunks.get(&key);
+                let resident = chunk.filter(|chunk| chunk.generation == self.generation).map(|chunk| chunk.lod);
+                let lod = self.select_lod(distance, resident);
+                if resident != Some(lod) {
+                    // chunks waiting for a LOD change or a new generator are still drawn, so missing ones go first
+                    let priority = if chunk.is_some() { distance + 100.0 } else { distance };
+                    jobs.push(ChunkJob { key, lod, priority });
+                }
+            }
+        }
+        self.workers.schedule(jobs);
+        for (job, mut mesh) in self.workers.poll(self.streaming.build_budget) {
+            if let SeamMode::Skirts(depth) = self.seams {
+                mesh.add_skirts(depth);
+            }
+            let mut chunk = Chunk::new(&mesh, job.lod, self.textures.clone());
+            chunk.generation = self.generation;
+            self.chunks.insert(job.key, chunk);
+        }
+        if self.seams == SeamMode::Stitch {
+            self.stitch_chunks();
+        }
+
+        let ctx = unsafe { macroquad::window::get_internal_gl().quad_context };
+        ctx.apply_pipeline(&self.pipeline);
+        ctx.apply_uniforms(UniformsSource::table(&shader::Uniforms {
+            projection: camera.matrix(),
+            model: Mat4::IDENTITY,
+            light_dir: -light_dir.normalize(),
+            bands: self.bands,
+            heights: Vec2::new(self.heights.offset, self.heights.scale),
+        }));
+        let frustum = Frustum::from_camera(camera);
+        self.frame += 1;
+        self.stats = DrawStats::default();
+        for chunk in self.chunks.values_mut() {
+            if frustum.intersects_aabb(chunk.min, chunk.max) {
+                chunk.draw(ctx);
+                chunk.last_visible = self.frame;
+                self.stats.drawn += 1;
+            } else {
+                self.stats.culled += 1;
+            }
+        }
+        ctx.end_render_pass();
+
+        self.evict(camera);
+    }
+
+    /// Height of the terrain at world position `(x, z)`, using the same mapping as the chunk meshes.
+    /// With erosion, places whose tile has not been built yet get the uneroded height.
+    pub fn height_at(&self, x: f32, z: f32) -> f32 {
+        let key = self.chunk_key(x, z);
+        let tiles = self.workers.tiles();
+        let size = self.chunk_size;
+        let (u, v) = (x / size - key.x as f32, z / size - key.y as f32);
+        match self.sampling {
+            // eroded heights only exist in the tiles, where none is built the generator has to do
+            // only eroded terrain needs the tiles, which sit behind a lock shared with the workers
+            HeightSampling::Exact => self.heights.apply(match (!self.erosion.is_empty()).then(|| tiles.cached_heights(key, 0)).flatten() {
+                Some(tile) => tile.sample(u * (tile.width - 1) as f32, v * (tile.height - 1) as f32),
+                None => terrain_height(&*self.generator, x, z, self.terrain_scale),
+            }),
+            HeightSampling::Bilinear => {
+                let lod = self.chunks.get(&key).map_or(0, |chunk| chunk.lod);
+                let (x_divisions, y_divisions) = self.lod_divisions(lod);
+                let (x_cells, y_cells) = ((x_divisions - 1) as f32, (y_divisions - 1) as f32);
+                let (u, v) = (u * x_cells, v * y_cells);
+                let (xi, yi) = (u.floor().min(x_cells - 1.), v.floor().min(y_cells - 1.));
+                let (s, t) = (u - xi, v - yi);
+                let tile = tiles.cached_heights(key, lod);
+                // vertex positions are computed exactly like in ChunkMesh::new
+                let height = |xi: f32, yi: f32| self.heights.apply(match &tile {
+                    Some(tile) => tile.get(xi as usize, yi as usize),
+                    None => terrain_height(&*self.generator, xi / x_cells * size + key.x as f32 * size, yi / y_cells * size + key.y as f32 * size, self.terrain_scale),
                 });
+                cell_height([height(xi, yi), height(xi + 1., yi), height(xi, yi + 1.), height(xi + 1., yi + 1.)], s, t)
             }
         }
-        if added >= 1 { dbg!(added);}
-        if self.chunks.len() >= 600 {
-            
-            self.chunks.retain(|key, _| (*key-camera_offset).length_squared() < 200);
+    }
+
+    /// Upwards facing surface normal at world position `(x, z)`, from central differences over
+    /// one quad of the most detailed LOD.
+    pub fn normal_at(&self, x: f32, z: f32) -> Vec3 {
+        let (step_x, step_z) = (self.chunk_size / (self.divisions.0 - 1) as f32, self.chunk_size / (self.divisions.1 - 1) as f32);
+        let dx = self.height_at(x + step_x, z) - self.height_at(x - step_x, z);
+        let dz = self.height_at(x, z + step_z) - self.height_at(x, z - step_z);
+        Vec3::new(-dx / (2. * step_x), 1.0, -dz / (2. * step_z)).normalize()
+    }
+
+    /// Angle between the surface and the horizontal plane in radians.
+    pub fn slope_at(&self, x: f32, z: f32) -> f32 {
+        self.normal_at(x, z).y.clamp(-1.0, 1.0).acos()
+    }
+
+    /// Casts a ray against the terrain as sampled by `height_at`. `dir` does not need to be normalized.
+    pub fn raycast(&self, origin: Vec3, dir: Vec3, max_dist: f32) -> Option<Hit> {
+        let dir = dir.normalize();
+        let step = self.chunk_size / (self.divisions.0.max(self.divisions.1) - 1) as f32;
+        let distance = march_ray(|x, z| self.height_at(x, z), origin, dir, max_dist, step)?;
+        let position = origin + distance * dir;
+        Some(Hit {
+            position,
+            normal: self.normal_at(position.x, position.z),
+            distance,
+            chunk: self.chunk_key(position.x, position.z),
+        })
+    }
+
+    fn evict(&mut self, camera: &Camera3D) {
+        let (load_radius, unload_radius, chunk_size) = (self.streaming.load_radius, self.streaming.unload_radius, self.chunk_size);
+        let distance = |key: &IVec2| ((key.as_vec2() + 0.5) * chunk_size).distance(camera.position.xz());
+        self.chunks.retain(|key, _| distance(key) <= unload_radius);
+        if self.chunks.len() <= self.streaming.max_resident {
+            return;
+        }
+        let mut candidates: Vec<(IVec2, u64)> = self.chunks.iter()
+            .filter(|(key, chunk)| chunk.last_visible != self.frame && distance(key) > load_radius)
+            .map(|(key, chunk)| (*key, chunk.last_visible))
+            .collect();
+        candidates.sort_by_key(|(_, last_visible)| *last_visible);
+        let excess = self.chunks.len() - self.streaming.max_resident;
+        for (key, _) in candidates.into_iter().take(excess) {
+            self.chunks.remove(&key);
         }
+    }
 
-        for (_, chunk) in &mut self.chunks {
-            chunk.draw(&self.pipeline, camera, light_dir);
+    fn stitch_chunks(&mut self) {
+        let neighbours = [IVec2::new(-1, 0), IVec2::new(1, 0), IVec2::new(0, -1), IVec2::new(0, 1)];
+        let stitches: Vec<(IVec2, [usize; 4])> = self.chunks.iter().map(|(key, chunk)| {
+            let stitch = neighbours.map(|direction| match self.chunks.get(&(*key + direction)) {
+                // the x neighbours share an edge along y and the other way around
+                Some(neighbour) if direction.x != 0 => edge_stitch(chunk.divisions.1, neighbour.divisions.1),
+                Some(neighbour) => edge_stitch(chunk.divisions.0, neighbour.divisions.0),
+                None => 1,
+            });
+            (*key, stitch)
+        }).collect();
+        for (key, stitch) in stitches {
+            self.chunks.get_mut(&key).unwrap().restitch(stitch);
         }
     }
 }
@@ -239,6 +744,9 @@ mod shader {
     in vec2 texcoord;
 
     uniform vec3 light_dir = vec3(1.0, 0.0, 0.0);
+    uniform vec3 bands = vec3(0.25, 0.5, 0.75);
+    // offset and scale of the world space heights, bands are relative to them
+    uniform vec2 heights = vec2(0.0, 1.0);
     uniform sampler2D snow_texture;
     uniform sampler2D grass_texture;
     uniform sampler2D rock_texture;
@@ -249,20 +757,21 @@ mod shader {
     void main() {
         float diffuse = dot(light_dir, normalize(normal));
         diffuse = max(0.3, diffuse);
+        float height = (pos.y-heights.x)/heights.y;
         vec4 texcolor;
-        if (pos.y < 0.25) {
+        if (height < bands.x) {
             texcolor = texture(dirt_texture, texcoord);
         }
-        else if (0.25 <= pos.y && pos.y < 0.5) {
-            float t = 4.*(pos.y-0.25);
+        else if (height < bands.y) {
+            float t = (height-bands.x)/(bands.y-bands.x);
             texcolor = mix(texture(dirt_texture, texcoord), texture(grass_texture, texcoord), t);
         }
-        else if (0.5 <= pos.y && pos.y < 0.75) {
-            float t = 4.*(pos.y-0.5);
+        else if (height < bands.z) {
+            float t = (height-bands.y)/(bands.z-bands.y);
             texcolor = mix(texture(grass_texture, texcoord), texture(rock_texture, texcoord), t);
         }
         else {
-            float t = 4.*(pos.y-0.75);
+            float t = clamp((height-bands.z)/(1.0-bands.z), 0.0, 1.0);
             texcolor = mix(texture(rock_texture, texcoord), texture(snow_texture, texcoord), t);
         }
         FragColor = diffuse*texcolor ;
@@ -276,6 +785,8 @@ mod shader {
                     UniformDesc::new("model", UniformType::Mat4),
                     UniformDesc::new("projection", UniformType::Mat4),
                     UniformDesc::new("light_dir", UniformType::Float3),
+                    UniformDesc::new("bands", UniformType::Float3),
+                    UniformDesc::new("heights", UniformType::Float2),
                 ],
             },
         }
@@ -286,10 +797,12 @@ mod shader {
         pub model: Mat4,
         pub projection: Mat4,
         pub light_dir: Vec3,
+        pub bands: Vec3,
+        pub heights: Vec2,
     }
 }
 
-fn texture_from_png(ctx: &mut Box<&mut dyn RenderingBackend>, bytes: &[u8]) -> TextureId {
+fn texture_from_png(ctx: &mut dyn RenderingBackend, bytes: &[u8]) -> TextureId {
     let image = Image::from_file_with_format(
         bytes,
         None,
@@ -301,7 +814,7 @@ fn texture_from_png(ctx: &mut Box<&mut dyn RenderingBackend>, bytes: &[u8]) -> T
         TextureParams{
             kind: TextureKind::Texture2D,
             format: TextureFormat::RGBA8,
-            wrap: TextureWrap::Clamp,
+            wrap: TextureWrap::Repeat,
             min_filter: FilterMode::Linear,
             mag_filter: FilterMode::Linear,
             mipmap_filter: MipmapFilterMode::Linear,
@@ -312,13 +825,234 @@ fn texture_from_png(ctx: &mut Box<&mut dyn RenderingBackend>, bytes: &[u8]) -> T
         }
     );
     ctx.texture_generate_mipmaps(texture_id);
-    return texture_id;
+    texture_id
 }
 
 pub fn load_textures() -> Vec<TextureId> {
-    let mut ctx = Box::new(unsafe { macroquad::window::get_internal_gl().quad_context });
-    vec![texture_from_png(&mut ctx, include_bytes!("../assets/grass.png")),
-     texture_from_png(&mut ctx, include_bytes!("../assets/snow.png")),
-     texture_from_png(&mut ctx, include_bytes!("../assets/rock.png")),
-     texture_from_png(&mut ctx, include_bytes!("../assets/dirt.png"))]
+    let ctx = unsafe { macroquad::window::get_internal_gl().quad_context };
+    vec![texture_from_png(ctx, include_bytes!("../assets/grass.png")),
+     texture_from_png(ctx, include_bytes!("../assets/snow.png")),
+     texture_from_png(ctx, include_bytes!("../assets/rock.png")),
+     texture_from_png(ctx, include_bytes!("../assets/dirt.png"))]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::erosion::{HydraulicErosion, ThermalErosion};
+    use crate::generator::FbmParams;
+
+    /// Rises by `slope` per unit of `x`, constant along `y`.
+    struct Ramp {
+        slope: f64,
+    }
+
+    impl Generator<2> for Ramp {
+        fn sample(&self, point: [f64; 2]) -> f64 {
+            self.slope * point[0]
+        }
+    }
+
+    fn params(divisions: usize) -> MeshParams {
+        MeshParams {
+            chunk_size: 1.0,
+            divisions: (divisions, divisions),
+            terrain_scale: 1.0,
+            heights: HeightScale::default(),
+            erosion: Vec::new(),
+            seed: 0,
+        }
+    }
+
+    #[test]
+    fn cell_height_follows_the_mesh_triangles() {
+        let heights = [1.0, 2.0, 4.0, 8.0];
+        // vertex index xi * 2 + yi is (xi, yi) with height heights[xi + 2 * yi]
+        let corner = |index: u32| ((index / 2) as f32, (index % 2) as f32, heights[(index / 2 + 2 * (index % 2)) as usize]);
+        for triangle in ChunkMesh::grid_indices((2, 2), [1; 4]).chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| corner(triangle[i]));
+            let (s, t) = ((a.0 + b.0 + c.0) / 3.0, (a.1 + b.1 + c.1) / 3.0);
+            assert!((cell_height(heights, s, t) - (a.2 + b.2 + c.2) / 3.0).abs() < 1e-6);
+        }
+        for (i, (s, t)) in [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)].into_iter().enumerate() {
+            assert_eq!(cell_height(heights, s, t), heights[i]);
+        }
+        // the diagonal is a straight edge, unlike on a bilinear patch
+        assert_eq!(cell_height(heights, 0.5, 0.5), 0.5 * (heights[0] + heights[3]));
+    }
+
+    #[test]
+    fn mesh_counts() {
+        let tiles = TileCache::new(Arc::new(Ramp { slope: 0.0 }), params(33), 64);
+        for lod in 0..7 {
+            let mesh = ChunkMesh::new(&tiles, IVec2::new(2, -3), lod);
+            let (x_divisions, y_divisions) = mesh.divisions;
+            assert_eq!(mesh.divisions, lod_divisions((33, 33), lod));
+            assert_eq!(mesh.vertices.len(), x_divisions * y_divisions);
+            assert_eq!(mesh.indices.len(), 6 * (x_divisions - 1) * (y_divisions - 1));
+            assert!(mesh.indices.iter().all(|&index| (index as usize) < mesh.vertices.len()));
+            let (min, max) = mesh.bounds();
+            assert_eq!((min.x, min.z, max.x, max.z), (2.0, -3.0, 3.0, -2.0));
+        }
+    }
+
+    #[test]
+    fn flat_normals_point_straight_down() {
+        let tiles = TileCache::new(Arc::new(Ramp { slope: 0.0 }), params(17), 64);
+        let mesh = ChunkMesh::new(&tiles, IVec2::ZERO, 0);
+        for vertex in &mesh.vertices {
+            assert_eq!(vertex.pos.y, 0.5);
+            assert!(vertex.normal.abs_diff_eq(Vec3::NEG_Y, 1e-6), "{}", vertex.normal);
+        }
+    }
+
+    #[test]
+    fn sloped_normals_match_the_gradient() {
+        // terrain_height halves the generator, so the ground rises by 0.25 per unit of x
+        let tiles = TileCache::new(Arc::new(Ramp { slope: 0.5 }), params(17), 64);
+        let expected = -Vec3::new(-0.25, 1.0, 0.0).normalize();
+        for key in [IVec2::ZERO, IVec2::new(-1, 4)] {
+            let mesh = ChunkMesh::new(&tiles, key, 1);
+            for vertex in &mesh.vertices {
+                assert!((vertex.pos.y - (0.5 + 0.25 * vertex.pos.x)).abs() < 1e-5);
+                // the border vertices take their neighbours from the adjacent tiles
+                assert!(vertex.normal.abs_diff_eq(expected, 1e-5), "{} at {}", vertex.normal, vertex.pos);
+            }
+        }
+    }
+
+    fn noise_tiles(erosion: Vec<ErosionStage>) -> TileCache<impl Generator<2>> {
+        noise_tiles_with(33, erosion)
+    }
+
+    fn noise_tiles_with(divisions: usize, erosion: Vec<ErosionStage>) -> TileCache<impl Generator<2>> {
+        let params = MeshParams { terrain_scale: 45.0, erosion, seed: 5, ..params(divisions) };
+        TileCache::new(Arc::new(Source::simplex(5).fbm(5, 0.013, 2.0, 0.5)), params, 256)
+    }
+
+    fn erosion() -> Vec<ErosionStage> {
+        vec![
+            ErosionStage::Hydraulic(HydraulicErosion { halo: 8, ..HydraulicErosion::default() }),
+            ErosionStage::Thermal(ThermalErosion { iterations: 4, ..ThermalErosion::default() }),
+        ]
+    }
+
+    #[test]
+    fn coarse_neighbour_shares_edge_positions() {
+        // small grids run out of detail after a few LODs, so neighbouring LODs may match
+        let cases = [(3, 0), (3, 1), (3, 2), (5, 1), (5, 2), (5, 3), (33, 0), (33, 4)];
+        for (divisions, lod) in cases {
+            for erosion in [Vec::new(), erosion()] {
+                let tiles = noise_tiles_with(divisions, erosion);
+                let fine = ChunkMesh::new(&tiles, IVec2::new(0, 0), lod);
+                let coarse = ChunkMesh::new(&tiles, IVec2::new(1, 0), lod + 1);
+                let (fine_x, fine_y) = fine.divisions;
+                let (_, coarse_y) = coarse.divisions;
+                let ratio = edge_stitch(fine_y, coarse_y);
+                assert_eq!(ratio, if fine_y > 2 { 2 } else { 1 });
+                // the -x edge of the coarse chunk against every `ratio`th vertex of the fine +x edge
+                for yi in 0..coarse_y {
+                    assert_eq!(coarse.vertices[yi].pos, fine.vertices[(fine_x - 1) * fine_y + yi * ratio].pos);
+                }
+
+                // stitched, the fine +x edge only uses the vertices the coarse edge has as well
+                let indices = ChunkMesh::grid_indices(fine.divisions, [1, ratio, 1, 1]);
+                assert_eq!(indices.len(), fine.indices.len());
+                let edge: Vec<usize> = indices.iter().map(|&index| index as usize)
+                    .filter(|index| index / fine_y == fine_x - 1)
+                    .map(|index| index % fine_y)
+                    .collect();
+                assert!(edge.iter().all(|yi| yi % ratio == 0));
+                assert!((0..fine_y).step_by(ratio).all(|yi| edge.contains(&yi)));
+            }
+        }
+    }
+
+    #[test]
+    fn skirts_hang_below_every_edge() {
+        let mut mesh = ChunkMesh::new(&noise_tiles(Vec::new()), IVec2::ZERO, 2);
+        let (x_divisions, y_divisions) = mesh.divisions;
+        let (vertices, indices) = (mesh.vertices.len(), mesh.indices.len());
+        mesh.add_skirts(0.5);
+        assert_eq!(mesh.vertices.len(), vertices + 2 * (x_divisions + y_divisions));
+        assert_eq!(mesh.indices.len(), indices + 12 * (x_divisions + y_divisions - 2));
+        assert!(mesh.indices.iter().all(|&index| (index as usize) < mesh.vertices.len()));
+        // the first skirt hangs below the -x edge
+        for yi in 0..y_divisions {
+            let (top, bottom) = (mesh.vertices[yi].pos, mesh.vertices[vertices + yi].pos);
+            assert_eq!(bottom, top - Vec3::new(0.0, 0.5, 0.0));
+        }
+    }
+
+    #[test]
+    fn ray_hits_plane() {
+        let plane = |_: f32, _: f32| 0.25;
+        let dir = Vec3::new(1.0, -1.0, 0.0).normalize();
+        let distance = march_ray(plane, Vec3::new(0.0, 1.0, 0.0), dir, 10.0, 0.1).unwrap();
+        assert!((distance - 0.75 * 2f32.sqrt()).abs() < 1e-5, "{distance}");
+    }
+
+    #[test]
+    fn ray_refines_the_crossing_by_bisection() {
+        // the ground rises by 0.5 per unit of x and meets the horizontal ray at x = 2
+        let slope = |x: f32, _: f32| 0.5 * x;
+        for step in [0.3, 0.7, 1.5] {
+            let distance = march_ray(slope, Vec3::new(0.0, 1.0, 0.0), Vec3::X, 10.0, step).unwrap();
+            assert!((distance - 2.0).abs() < step / 10_000.0, "{distance} with step {step}");
+        }
+    }
+
+    #[test]
+    fn ray_below_the_surface_misses() {
+        let plane = |_: f32, _: f32| 0.25;
+        assert_eq!(march_ray(plane, Vec3::ZERO, Vec3::NEG_Y, 10.0, 0.1), None);
+        assert_eq!(march_ray(plane, Vec3::ZERO, Vec3::Y, 10.0, 0.1), None);
+    }
+
+    #[test]
+    fn ray_misses_beyond_max_dist() {
+        let plane = |_: f32, _: f32| 0.0;
+        // parallel to the ground
+        assert_eq!(march_ray(plane, Vec3::new(0.0, 1.0, 0.0), Vec3::Z, 100.0, 0.5), None);
+        // would hit after 2 units
+        let dir = Vec3::new(1.0, -1.0, 0.0).normalize();
+        assert_eq!(march_ray(plane, Vec3::new(0.0, 2f32.sqrt(), 0.0), dir, 1.9, 0.5), None);
+        assert!(march_ray(plane, Vec3::new(0.0, 2f32.sqrt(), 0.0), dir, 2.1, 0.5).is_some());
+    }
+
+    fn vertex_bits(mesh: &ChunkMesh) -> Vec<u32> {
+        mesh.vertices.iter().flat_map(|vertex| {
+            let Vertex { pos, uv, color, normal } = *vertex;
+            [pos.x, pos.y, pos.z, uv.x, uv.y, color.x, color.y, color.z, color.w, normal.x, normal.y, normal.z]
+        }).map(f32::to_bits).collect()
+    }
+
+    #[test]
+    fn same_seed_builds_identical_meshes() {
+        let mesh = |seed: u64, erosion: Vec<ErosionStage>| {
+            let params = MeshParams { terrain_scale: 45.0, erosion, seed, ..params(33) };
+            let tiles = TileCache::new(Arc::new(FbmParams::default().generator(seed)), params, 256);
+            ChunkMesh::new(&tiles, IVec2::new(3, -2), 0)
+        };
+        for erosion in [Vec::new(), erosion()] {
+            let (first, second) = (mesh(42, erosion.clone()), mesh(42, erosion.clone()));
+            assert_eq!(vertex_bits(&first), vertex_bits(&second));
+            assert_eq!(first.indices, second.indices);
+            assert_ne!(vertex_bits(&first), vertex_bits(&mesh(43, erosion)));
+        }
+    }
+
+    #[test]
+    fn lod_vertices_land_on_detailed_vertices() {
+        for divisions in [2, 3, 5, 33, 65, 129] {
+            assert!(valid_divisions(divisions));
+            for lod in 0..10 {
+                let (coarse, _) = lod_divisions((divisions, divisions), lod);
+                assert_eq!((divisions - 1) % (coarse - 1), 0, "{divisions} divisions at lod {lod}");
+            }
+        }
+        for divisions in [0, 1, 4, 50, 64, 66] {
+            assert!(!valid_divisions(divisions));
+        }
+    }
 }
@@ -4,8 +4,27 @@ use macroquad::prelude::*;
 
 pub mod camera;
 pub use crate::camera::*;
+pub mod camera_path;
+pub use crate::camera_path::*;
+pub mod config;
+pub use crate::config::*;
+pub mod erosion;
+pub use crate::erosion::*;
+pub mod export;
+pub use crate::export::*;
+pub mod generator;
+pub use crate::generator::*;
+pub mod grid;
+pub use crate::grid::*;
 pub mod heightmap;
 pub use crate::heightmap::*;
+pub mod panel;
+pub use crate::panel::*;
+pub mod raster;
+pub use crate::raster::*;
+pub mod tiles;
+pub use crate::tiles::*;
+pub mod worker;
 
 fn window_conf() -> Conf {
     Conf {
@@ -22,6 +41,14 @@ fn window_conf() -> Conf {
     }
 }
 
+/// Builds the terrain from the description file if one was given, otherwise uses plain fbm noise.
+fn terrain_generator(terrain: Option<&str>, seed: u64, terrain_scale: f64, fbm: &FbmParams) -> Result<BoxedGenerator, String> {
+    match terrain {
+        Some(path) => load_generator(path, seed, terrain_scale),
+        None => Ok(fbm.generator(seed)),
+    }
+}
+
 #[cfg(target_family = "wasm")]
 fn enable_wireframe() {}
 #[cfg(target_family = "wasm")]
@@ -36,54 +63,252 @@ fn disable_wireframe() {
     use macroquad::miniquad::gl::{self, GL_FILL, GL_FRONT_AND_BACK};
     unsafe {gl::glPolygonMode(GL_FRONT_AND_BACK, GL_FILL)};}
 
-#[macroquad::main(window_conf)]
-async fn main() {
+/// Writes the `--export` files without opening a window.
+fn export(args: &Args) -> Result<(), String> {
+    let (min, max) = args.region.ok_or("--export: needs a --region")?;
+    let config = load_config(CONFIG_PATH)?;
+    let settings = Settings::from_config(&Section::root(&config)).map_err(|err| format!("{CONFIG_PATH}: {err}"))?;
+    let seed = args.seed.unwrap_or_else(|| rand::rand() as u64);
+    let generator = terrain_generator(args.terrain.as_deref(), seed, settings.terrain_scale, &settings.fbm)?;
+    let divisions = (settings.divisions, settings.divisions);
+    let params = MeshParams {
+        chunk_size: settings.chunk_size,
+        divisions,
+        terrain_scale: settings.terrain_scale,
+        heights: settings.heights,
+        erosion: settings.erosion,
+        seed,
+    };
+    let tiles = TileCache::new(std::sync::Arc::new(generator), params, StreamingConfig::default().cached_tiles);
+    let region = match args.resolution {
+        Some(resolution) => ExportRegion { min, max, resolution },
+        None => ExportRegion::with_mesh_resolution(min, max, settings.chunk_size, divisions),
+    };
+    for path in &args.export {
+        export_region(&tiles, &region, path)?;
+        println!("{path}: {}x{} heights of seed {seed}", region.resolution.0, region.resolution.1);
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = Args::parse(std::env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("{err}");
+        std::process::exit(2);
+    });
+    rand::srand((miniquad::date::now()*1000.0) as u64);
+    if !args.export.is_empty() {
+        if let Err(err) = export(&args) {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    macroquad::Window::from_config(window_conf(), run(args));
+}
+
+async fn run(args: Args) {
     let mut camera = Camera3D {
         position: vec3(3., 0.8, 0.0),
         up: vec3(0., 1., 0.),
         target: vec3(0., 0., 0.),
         ..Default::default()
     };
-    use libnoise::prelude::*;
-    let generator = libnoise::Source::simplex(rand::rand() as u64).fbm(5, 0.013, 2.0, 0.5);
-    let mut light_dir = Vec3::new(10.0, 2.0, 0.0).normalize();
-    let mut heightmap = Heightmap::new(generator, (50, 50), 45.);
+    let config = load_config(CONFIG_PATH).unwrap_or_else(|err| {
+        eprintln!("{err}");
+        toml::Table::new()
+    });
+    let mut settings = Settings::from_config(&Section::root(&config)).unwrap_or_else(|err| {
+        eprintln!("{CONFIG_PATH}: {err}");
+        Settings::default()
+    });
+    settings.seed = args.seed.unwrap_or_else(|| rand::rand() as u64);
+    let generator = terrain_generator(args.terrain.as_deref(), settings.seed, settings.terrain_scale, &settings.fbm).unwrap_or_else(|err| {
+        eprintln!("{err}");
+        std::process::exit(1);
+    });
+    let mut heightmap = Heightmap::new(generator, settings.chunk_size, (settings.divisions, settings.divisions), settings.terrain_scale, settings.heights, StreamingConfig::default());
+    heightmap.erosion = settings.erosion.clone();
+    heightmap.seed = settings.seed;
+    heightmap.invalidate();
+    let mut panel = SettingsPanel::default();
     let mut fly_forward = true;
+    let mut controller = CameraController::from_config(&Section::root(&config)).unwrap_or_else(|err| {
+        eprintln!("{CONFIG_PATH}: {err}");
+        CameraController::default()
+    });
+    let mut terrain_watcher = args.terrain.as_deref().map(FileWatcher::new);
+    // the last failed reload of the terrain file, shown until a reload succeeds
+    let mut terrain_error: Option<String> = None;
+    let mut camera_path = CameraPath::default();
+    // seconds since recording or playback started
+    let mut recording: Option<f32> = None;
+    let mut playback: Option<f32> = None;
     let mut dir = 1.;
     loop {
         // input
         let dt = get_frame_time();
-        if fly_forward {
+        // walking moves through update_walk only, which keeps to max_slope
+        if fly_forward && controller.mode == CameraMode::Free {
             camera_move_forward(&mut camera, dt, true);
         }
-        update_camera(&mut camera);
+        if let Some(time) = &mut playback {
+            *time += dt;
+            if !camera_path.apply(&mut camera, *time) || *time > camera_path.duration() {
+                playback = None;
+            }
+        } else if !panel.wants_mouse() {
+            match controller.mode {
+                CameraMode::Free => {
+                    controller.update(&mut camera);
+                    if controller.collide {
+                        camera_clamp_to_ground(&mut camera, controller.ground_clearance, |x, z| heightmap.height_at(x, z));
+                    }
+                }
+                CameraMode::Walk => controller.update_walk(&mut camera, |x, z| heightmap.height_at(x, z)),
+                CameraMode::Orbit => {
+                    if is_mouse_button_pressed(MouseButton::Right) {
+                        let (ray_origin, ray_dir) = mouse_ray(&camera);
+                        if let Some(hit) = heightmap.raycast(ray_origin, ray_dir, 30.0) {
+                            camera_set_pivot(&mut camera, hit.position);
+                        }
+                    }
+                    controller.update_orbit(&mut camera, |x, z| heightmap.height_at(x, z));
+                }
+            }
+        }
+        if let Some(time) = &mut recording {
+            *time += dt;
+            if *time - camera_path.duration() >= 0.5 {
+                camera_path.record(&camera, *time);
+            }
+        }
         if is_key_down(KeyCode::Escape) {
             break;
         }
         if is_key_pressed(KeyCode::T) {
             fly_forward = !fly_forward;
         }
+        if is_key_pressed(KeyCode::R) {
+            if let Some(time) = recording.take() {
+                // keep the stretch since the last keyframe
+                if time > camera_path.duration() {
+                    camera_path.record(&camera, time);
+                }
+                if let Err(err) = camera_path.save(CAMERA_PATH_FILE) {
+                    eprintln!("{err}");
+                }
+            } else {
+                camera_path = CameraPath::default();
+                camera_path.record(&camera, 0.0);
+                recording = Some(0.0);
+                playback = None;
+            }
+        }
+        // P starts playback of the saved path or stops a running one
+        if is_key_pressed(KeyCode::P) && recording.is_none() && playback.take().is_none() {
+            match CameraPath::load(CAMERA_PATH_FILE) {
+                Ok(loaded) => camera_path = loaded,
+                Err(err) => eprintln!("{err}"),
+            }
+            playback = Some(0.0);
+            fly_forward = false;
+        }
+        if terrain_watcher.as_mut().is_some_and(|watcher| watcher.changed()) {
+            match terrain_generator(args.terrain.as_deref(), settings.seed, settings.terrain_scale, &settings.fbm) {
+                Ok(generator) => {
+                    heightmap.swap_generator(generator);
+                    terrain_error = None;
+                }
+                Err(err) => {
+                    eprintln!("{err}");
+                    terrain_error = Some(err);
+                }
+            }
+        }
+        if is_key_pressed(KeyCode::N) {
+            let new_seed = rand::rand() as u64;
+            match terrain_generator(args.terrain.as_deref(), new_seed, settings.terrain_scale, &settings.fbm) {
+                Ok(generator) => {
+                    settings.seed = new_seed;
+                    heightmap.seed = new_seed;
+                    heightmap.set_generator(generator);
+                }
+                Err(err) => eprintln!("{err}"),
+            }
+        }
+        if is_key_pressed(KeyCode::C) {
+            controller.collide = !controller.collide;
+        }
+        if is_key_pressed(KeyCode::F) {
+            controller.mode = if controller.mode == CameraMode::Walk { CameraMode::Free } else { CameraMode::Walk };
+            controller.walker.vertical_velocity = 0.0;
+            controller.walker.grounded = false;
+            if controller.mode == CameraMode::Walk {
+                fly_forward = false;
+            }
+        }
+        if is_key_pressed(KeyCode::O) {
+            controller.mode = if controller.mode == CameraMode::Orbit { CameraMode::Free } else { CameraMode::Orbit };
+            if controller.mode == CameraMode::Orbit {
+                // orbit around the terrain point in the middle of the screen
+                let forward = get_camera_forward(&camera);
+                match heightmap.raycast(camera.position, forward, 30.0) {
+                    Some(hit) => camera.target = hit.position,
+                    None => camera.target = camera.position + forward,
+                }
+                fly_forward = false;
+            }
+        }
         if is_key_down(KeyCode::LeftShift) && is_key_down(KeyCode::W) {
             enable_wireframe();
         }
         if is_key_down(KeyCode::RightShift) && is_key_down(KeyCode::W) {
             disable_wireframe();
         }
-        if light_dir.y < 0. {
+        settings.sun_angle += settings.sun_speed*dir*dt;
+        if !(0.0..=std::f32::consts::PI).contains(&settings.sun_angle) {
             dir *= -1.;
+            settings.sun_angle = settings.sun_angle.clamp(0.0, std::f32::consts::PI);
         }
-        light_dir = rotate_vector_axis_angle(light_dir, vec3(0., 0., 1.), 0.3*dir*dt);
+        heightmap.bands = settings.bands;
 
         // drawing
         set_camera(&camera);
         clear_background(BLACK);
         draw_grid(20, 0.1, BLACK, GRAY);
-        heightmap.draw(&camera, light_dir);
+        heightmap.draw(&camera, settings.light_dir());
 
         // Back to screen space
         set_default_camera();
         draw_fps();
-        draw_text(&format!("{:?}", heightmap.chunks.len()), 10.0, 50.0, 20.0, WHITE);
+        draw_text(&format!("seed {}", settings.seed), 10.0, 30.0, 20.0, WHITE);
+        if let Some(err) = &terrain_error {
+            draw_text(err, 10.0, screen_height() - 20.0, 20.0, RED);
+        }
+        let info = [
+            format!("{} chunks, {} pending", heightmap.chunks.len(), heightmap.workers.in_flight()),
+            format!("{} drawn, {} culled", heightmap.stats.drawn, heightmap.stats.culled),
+        ];
+        if panel.ui(&mut settings, args.terrain.is_none(), &info) {
+            match terrain_generator(args.terrain.as_deref(), settings.seed, settings.terrain_scale, &settings.fbm) {
+                Ok(generator) => {
+                    heightmap.terrain_scale = settings.terrain_scale;
+                    heightmap.divisions = (settings.divisions, settings.divisions);
+                    heightmap.heights = settings.heights;
+                    heightmap.seed = settings.seed;
+                    heightmap.swap_generator(generator);
+                    if heightmap.chunk_size != settings.chunk_size {
+                        heightmap.set_chunk_size(settings.chunk_size);
+                    }
+                }
+                Err(err) => eprintln!("{err}"),
+            }
+        }
+        let (ray_origin, ray_dir) = mouse_ray(&camera);
+        if let Some(hit) = heightmap.raycast(ray_origin, ray_dir, 30.0) {
+            draw_text(&format!("cursor: ({:.2}, {:.2}) height {:.3}", hit.position.x, hit.position.z, hit.position.y), 10.0, 50.0, 20.0, WHITE);
+        }
 
         next_frame().await
     }
@@ -0,0 +1,190 @@
+use macroquad::prelude::*;
+use macroquad::ui::{hash, root_ui, widgets};
+
+use crate::config::Section;
+use crate::erosion::ErosionStage;
+use crate::generator::FbmParams;
+use crate::heightmap::{valid_divisions, HeightScale};
+
+/// Everything the settings panel can change.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub seed: u64,
+    /// Only used without a terrain description file.
+    pub fbm: FbmParams,
+    pub terrain_scale: f64,
+    /// See `Heightmap::chunk_size`.
+    pub chunk_size: f32,
+    /// Vertex grid size of the most detailed chunks, the same in both directions, see `valid_divisions`.
+    pub divisions: usize,
+    pub heights: HeightScale,
+    pub erosion: Vec<ErosionStage>,
+    /// See `Heightmap::bands`.
+    pub bands: Vec3,
+    /// Height of the sun above the `+x` horizon in radians.
+    pub sun_angle: f32,
+    /// Radians per second the sun moves across the sky, 0 stops it.
+    pub sun_speed: f32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            seed: 0,
+            fbm: FbmParams::default(),
+            terrain_scale: 45.0,
+            chunk_size: 1.0,
+            divisions: 65,
+            heights: HeightScale::default(),
+            erosion: Vec::new(),
+            bands: Vec3::new(0.25, 0.5, 0.75),
+            sun_angle: 0.2,
+            sun_speed: 0.3,
+        }
+    }
+}
+
+impl Settings {
+    /// Reads `terrain_scale`, `chunk_size`, `divisions`, `height_scale` and `height_offset` from
+    /// the `[terrain]` table of the config file and the erosion stages from `[erosion]`.
+    pub fn from_config(config: &Section) -> Result<Settings, String> {
+        let mut settings = Settings::default();
+        let terrain = config.section("terrain")?;
+        terrain.f64("terrain_scale", &mut settings.terrain_scale)?;
+        terrain.f32("chunk_size", &mut settings.chunk_size)?;
+        if settings.chunk_size <= 0.0 {
+            return Err(format!("{}.chunk_size: must be positive", terrain.path));
+        }
+        terrain.usize("divisions", &mut settings.divisions)?;
+        if !valid_divisions(settings.divisions) {
+            return Err(format!("{}.divisions: must be a power of two plus one, e.g. 33, 65 or 129", terrain.path));
+        }
+        terrain.f32("height_scale", &mut settings.heights.scale)?;
+        if settings.heights.scale <= 0.0 {
+            return Err(format!("{}.height_scale: must be positive", terrain.path));
+        }
+        terrain.f32("height_offset", &mut settings.heights.offset)?;
+        settings.erosion = ErosionStage::from_config(config)?;
+        Ok(settings)
+    }
+
+    pub fn light_dir(&self) -> Vec3 {
+        Vec3::new(self.sun_angle.cos(), self.sun_angle.sin(), 0.0)
+    }
+}
+
+/// A window with sliders for the `Settings`, toggled with `toggle_key`.
+pub struct SettingsPanel {
+    pub open: bool,
+    pub toggle_key: KeyCode,
+    seed_text: String,
+    /// A setting that shapes the terrain changed but has not been reported yet.
+    pending: bool,
+}
+
+impl Default for SettingsPanel {
+    fn default() -> Self {
+        SettingsPanel { open: false, toggle_key: KeyCode::Tab, seed_text: String::new(), pending: false }
+    }
+}
+
+const POSITION: Vec2 = vec2(10.0, 110.0);
+const SIZE: Vec2 = vec2(340.0, 480.0);
+
+impl SettingsPanel {
+    /// Whether the panel is open and under the mouse, so mouse input belongs to it.
+    pub fn wants_mouse(&self) -> bool {
+        self.open && root_ui().is_mouse_over(mouse_position().into())
+    }
+
+    /// Handles the toggle key and draws the panel with `info` lines at the top. Returns true if
+    /// a setting that shapes the terrain changed, which means the generator has to be rebuilt
+    /// and the chunks invalidated. While the mouse button is down the change is held back, so
+    /// dragging a slider rebuilds the terrain once on release. `fbm` is only shown if `edit_fbm`
+    /// is set.
+    pub fn ui(&mut self, settings: &mut Settings, edit_fbm: bool, info: &[String]) -> bool {
+        if is_key_pressed(self.toggle_key) {
+            self.open = !self.open;
+            self.seed_text = settings.seed.to_string();
+        }
+        if !self.open {
+            return self.release_pending();
+        }
+        let before = settings.clone();
+        widgets::Window::new(hash!(), POSITION, SIZE).label("Settings").ui(&mut root_ui(), |ui| {
+            for line in info {
+                ui.label(None, line);
+            }
+            ui.separator();
+
+            ui.label(None, &format!("seed {}", settings.seed));
+            ui.input_text(hash!(), "new seed", &mut self.seed_text);
+            if ui.button(None, "apply") {
+                match self.seed_text.trim().parse() {
+                    Ok(seed) => settings.seed = seed,
+                    Err(_) => self.seed_text = settings.seed.to_string(),
+                }
+            }
+            ui.same_line(0.0);
+            if ui.button(None, "random") {
+                settings.seed = rand::rand() as u64;
+                self.seed_text = settings.seed.to_string();
+            }
+
+            if edit_fbm {
+                ui.separator();
+                let fbm = &mut settings.fbm;
+                ui.drag(hash!(), "octaves", (1, 12), &mut fbm.octaves);
+                slider_f64(ui, hash!(), "frequency", 0.001..0.1, &mut fbm.frequency);
+                slider_f64(ui, hash!(), "lacunarity", 1.0..4.0, &mut fbm.lacunarity);
+                slider_f64(ui, hash!(), "persistence", 0.0..1.0, &mut fbm.persistence);
+            }
+
+            ui.separator();
+            slider_f64(ui, hash!(), "terrain scale", 1.0..200.0, &mut settings.terrain_scale);
+            ui.slider(hash!(), "chunk size", 0.25..8.0, &mut settings.chunk_size);
+            // picks k of 2^k + 1, the only divisions that halve cleanly down the LODs
+            let mut exponent = (settings.divisions - 1).trailing_zeros();
+            ui.drag(hash!(), &format!("divisions {}", settings.divisions), (1, 8), &mut exponent);
+            settings.divisions = (1 << exponent) + 1;
+            ui.slider(hash!(), "height scale", 0.1..10.0, &mut settings.heights.scale);
+            ui.slider(hash!(), "height offset", -5.0..5.0, &mut settings.heights.offset);
+
+            ui.separator();
+            let bands = &mut settings.bands;
+            ui.slider(hash!(), "dirt/grass", 0.0..1.0, &mut bands.x);
+            ui.slider(hash!(), "grass/rock", 0.0..1.0, &mut bands.y);
+            ui.slider(hash!(), "rock/snow", 0.0..1.0, &mut bands.z);
+            // keep the bands in order, the shader divides by their distances
+            bands.y = bands.y.max(bands.x + 0.01);
+            bands.z = bands.z.max(bands.y + 0.01);
+
+            ui.separator();
+            ui.slider(hash!(), "sun angle", 0.0..std::f32::consts::PI, &mut settings.sun_angle);
+            ui.slider(hash!(), "sun speed", 0.0..2.0, &mut settings.sun_speed);
+        });
+        self.pending |= settings.seed != before.seed
+            || (edit_fbm && settings.fbm != before.fbm)
+            || settings.terrain_scale != before.terrain_scale
+            || settings.chunk_size != before.chunk_size
+            || settings.divisions != before.divisions
+            || settings.heights != before.heights;
+        self.release_pending()
+    }
+
+    fn release_pending(&mut self) -> bool {
+        if !self.pending || is_mouse_button_down(MouseButton::Left) {
+            return false;
+        }
+        self.pending = false;
+        true
+    }
+}
+
+fn slider_f64(ui: &mut macroquad::ui::Ui, id: macroquad::ui::Id, label: &str, range: std::ops::Range<f32>, value: &mut f64) {
+    let mut v = *value as f32;
+    ui.slider(id, label, range, &mut v);
+    if v != *value as f32 {
+        *value = v as f64;
+    }
+}
@@ -0,0 +1,229 @@
+use libnoise::prelude::*;
+use std::io::BufReader;
+
+use crate::generator::BoxedGenerator;
+use crate::grid::HeightGrid;
+
+/// File formats of height rasters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RasterFormat {
+    /// 8 or 16 bit grayscale PNG.
+    Png,
+    /// Single band TIFF of any sample type, e.g. a GeoTIFF DEM.
+    Tiff,
+    /// Headerless little-endian 32 bit floats, row by row.
+    RawF32,
+    /// Headerless little-endian 16 bit unsigned integers, row by row.
+    RawU16,
+}
+
+impl RasterFormat {
+    /// Guesses the format from the file extension.
+    pub fn from_path(path: &str) -> Option<RasterFormat> {
+        let extension = std::path::Path::new(path).extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "png" => Some(RasterFormat::Png),
+            "tif" | "tiff" => Some(RasterFormat::Tiff),
+            "f32" | "r32" => Some(RasterFormat::RawF32),
+            "u16" | "r16" | "raw" => Some(RasterFormat::RawU16),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<RasterFormat> {
+        match name {
+            "png" => Some(RasterFormat::Png),
+            "tiff" => Some(RasterFormat::Tiff),
+            "f32" => Some(RasterFormat::RawF32),
+            "u16" => Some(RasterFormat::RawU16),
+            _ => None,
+        }
+    }
+}
+
+/// Reads the samples of a height raster as they are stored. Raw formats carry no size, so they
+/// need the `width` of a row. Samples marked as missing by a GeoTIFF's nodata tag become NaN.
+pub fn load_raster(path: &str, format: RasterFormat, width: Option<usize>) -> Result<HeightGrid, String> {
+    let open = || std::fs::File::open(path).map(BufReader::new).map_err(|err| format!("{path}: {err}"));
+    match format {
+        RasterFormat::Png => {
+            let mut decoder = png::Decoder::new(open()?);
+            decoder.set_transformations(png::Transformations::IDENTITY);
+            let mut reader = decoder.read_info().map_err(|err| format!("{path}: {err}"))?;
+            let mut buffer = vec![0; reader.output_buffer_size()];
+            let info = reader.next_frame(&mut buffer).map_err(|err| format!("{path}: {err}"))?;
+            let (width, height) = (info.width as usize, info.height as usize);
+            let data = match (info.color_type, info.bit_depth) {
+                (png::ColorType::Grayscale, png::BitDepth::Sixteen) => {
+                    buffer[..info.buffer_size()].chunks_exact(2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]) as f32).collect()
+                }
+                (png::ColorType::Grayscale, png::BitDepth::Eight) => buffer[..info.buffer_size()].iter().map(|&value| value as f32).collect(),
+                (color_type, bit_depth) => {
+                    return Err(format!("{path}: expected an 8 or 16 bit grayscale image, not {bit_depth:?} bit {color_type:?}"));
+                }
+            };
+            Ok(HeightGrid { width, height, data })
+        }
+        RasterFormat::Tiff => {
+            use tiff::decoder::{Decoder, DecodingResult};
+            let tiff_error = |err: tiff::TiffError| format!("{path}: {err}");
+            let mut decoder = Decoder::new(open()?).map_err(tiff_error)?;
+            match decoder.colortype().map_err(tiff_error)? {
+                tiff::ColorType::Gray(_) => {}
+                color_type => return Err(format!("{path}: expected a single band, not {color_type:?}")),
+            }
+            let (width, height) = decoder.dimensions().map_err(tiff_error)?;
+            let nodata = decoder.get_tag_ascii_string(tiff::tags::Tag::GdalNodata).ok()
+                .and_then(|nodata| nodata.trim_matches(|c: char| c == '\0' || c.is_whitespace()).parse::<f64>().ok());
+            let mut data: Vec<f32> = match decoder.read_image().map_err(tiff_error)? {
+                DecodingResult::U8(data) => data.into_iter().map(|value| value as f32).collect(),
+                DecodingResult::U16(data) => data.into_iter().map(|value| value as f32).collect(),
+                DecodingResult::U32(data) => data.into_iter().map(|value| value as f32).collect(),
+                DecodingResult::U64(data) => data.into_iter().map(|value| value as f32).collect(),
+                DecodingResult::I8(data) => data.into_iter().map(|value| value as f32).collect(),
+                DecodingResult::I16(data) => data.into_iter().map(|value| value as f32).collect(),
+                DecodingResult::I32(data) => data.into_iter().map(|value| value as f32).collect(),
+                DecodingResult::I64(data) => data.into_iter().map(|value| value as f32).collect(),
+                DecodingResult::F32(data) => data,
+                DecodingResult::F64(data) => data.into_iter().map(|value| value as f32).collect(),
+            };
+            if let Some(nodata) = nodata {
+                let nodata = nodata as f32;
+                data.iter_mut().filter(|value| **value == nodata).for_each(|value| *value = f32::NAN);
+            }
+            Ok(HeightGrid { width: width as usize, height: height as usize, data })
+        }
+        RasterFormat::RawF32 | RasterFormat::RawU16 => {
+            let width = width.ok_or_else(|| format!("{path}: raw rasters need a width"))?;
+            let bytes = std::fs::read(path).map_err(|err| format!("{path}: {err}"))?;
+            let sample_size = if format == RasterFormat::RawF32 { 4 } else { 2 };
+            if width == 0 || !bytes.len().is_multiple_of(sample_size * width) {
+                return Err(format!("{path}: {} bytes are not whole rows of {width} samples", bytes.len()));
+            }
+            let data: Vec<f32> = if format == RasterFormat::RawF32 {
+                bytes.chunks_exact(4).map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])).collect()
+            } else {
+                bytes.chunks_exact(2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]) as f32).collect()
+            };
+            Ok(HeightGrid { width, height: data.len() / width, data })
+        }
+    }
+}
+
+/// Writes `grid` as a 16 bit grayscale PNG, with `range.0` and `range.1` mapped to black and
+/// white. Heights outside of the range are clamped.
+pub fn save_png16(grid: &HeightGrid, path: &str, range: (f32, f32)) -> Result<(), String> {
+    let file = std::fs::File::create(path).map_err(|err| format!("{path}: {err}"))?;
+    let mut encoder = png::Encoder::new(std::io::BufWriter::new(file), grid.width as u32, grid.height as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Sixteen);
+    let data: Vec<u8> = grid.data.iter().flat_map(|&height| {
+        let value = ((height - range.0) / (range.1 - range.0)).clamp(0.0, 1.0);
+        ((value * u16::MAX as f32).round() as u16).to_be_bytes()
+    }).collect();
+    let mut writer = encoder.write_header().map_err(|err| format!("{path}: {err}"))?;
+    writer.write_image_data(&data).map_err(|err| format!("{path}: {err}"))?;
+    writer.finish().map_err(|err| format!("{path}: {err}"))
+}
+
+/// Writes `grid` as headerless little-endian 32 bit floats, row by row, see `RasterFormat::RawF32`.
+pub fn save_raw_f32(grid: &HeightGrid, path: &str) -> Result<(), String> {
+    let data: Vec<u8> = grid.data.iter().flat_map(|height| height.to_le_bytes()).collect();
+    std::fs::write(path, data).map_err(|err| format!("{path}: {err}"))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+    Bilinear,
+    /// Catmull-Rom splines through the 4x4 closest samples. Smoother slopes, but may overshoot
+    /// the samples a little.
+    Bicubic,
+}
+
+/// What a `RasterSource` returns for points outside of its extent.
+pub enum Outside {
+    /// The closest sample on the border, so the edges stretch out to infinity.
+    Clamp,
+    /// Repeats the raster in both directions.
+    Wrap,
+    /// Samples another generator, e.g. noise around a real landscape.
+    Fallback(BoxedGenerator),
+}
+
+/// A generator backed by a height raster such as a DEM. The raster covers the rectangle from
+/// `origin` to `origin + size` in generator coordinates, which are world positions times the
+/// terrain scale, with each sample at the center of its cell. Samples between `range.0` and
+/// `range.1` are mapped to the `[-1, 1]` range of noise.
+pub struct RasterSource {
+    grid: HeightGrid,
+    pub origin: [f64; 2],
+    pub size: [f64; 2],
+    pub range: (f32, f32),
+    pub interpolation: Interpolation,
+    pub outside: Outside,
+}
+
+impl RasterSource {
+    /// Covers one unit per sample from the origin, with the lowest and highest sample mapped to
+    /// `-1` and `1`. Missing samples (NaN) are filled with the lowest one.
+    pub fn new(mut grid: HeightGrid) -> Result<RasterSource, String> {
+        if grid.width == 0 || grid.height == 0 {
+            return Err("the raster is empty".to_string());
+        }
+        let range = grid.data.iter().filter(|value| value.is_finite())
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(low, high), &value| (low.min(value), high.max(value)));
+        if range.0 > range.1 {
+            return Err("the raster has no valid samples".to_string());
+        }
+        grid.data.iter_mut().filter(|value| !value.is_finite()).for_each(|value| *value = range.0);
+        let size = [grid.width as f64, grid.height as f64];
+        Ok(RasterSource { grid, origin: [0.0, 0.0], size, range, interpolation: Interpolation::Bilinear, outside: Outside::Clamp })
+    }
+
+    /// The sample at column `x` and row `y`, which may lie outside the raster.
+    fn at(&self, x: isize, y: isize) -> f64 {
+        let (width, height) = (self.grid.width as isize, self.grid.height as isize);
+        let (x, y) = match self.outside {
+            Outside::Wrap => (x.rem_euclid(width), y.rem_euclid(height)),
+            _ => (x.clamp(0, width - 1), y.clamp(0, height - 1)),
+        };
+        self.grid.get(x as usize, y as usize) as f64
+    }
+}
+
+impl Generator<2> for RasterSource {
+    fn sample(&self, point: [f64; 2]) -> f64 {
+        // position relative to the extent, 0 to 1 inside
+        let (u, v) = ((point[0] - self.origin[0]) / self.size[0], (point[1] - self.origin[1]) / self.size[1]);
+        if let Outside::Fallback(fallback) = &self.outside
+            && (!(0.0..=1.0).contains(&u) || !(0.0..=1.0).contains(&v)) {
+            return fallback.sample(point);
+        }
+        let (x, y) = (u * self.grid.width as f64 - 0.5, v * self.grid.height as f64 - 0.5);
+        let (xi, yi) = (x.floor() as isize, y.floor() as isize);
+        let (s, t) = (x - xi as f64, y - yi as f64);
+        let value = match self.interpolation {
+            Interpolation::Bilinear => {
+                let top = self.at(xi, yi) + (self.at(xi + 1, yi) - self.at(xi, yi)) * s;
+                let bottom = self.at(xi, yi + 1) + (self.at(xi + 1, yi + 1) - self.at(xi, yi + 1)) * s;
+                top + (bottom - top) * t
+            }
+            Interpolation::Bicubic => {
+                let row = |y| catmull_rom([-1, 0, 1, 2].map(|dx| self.at(xi + dx, y)), s);
+                catmull_rom([-1, 0, 1, 2].map(|dy| row(yi + dy)), t)
+            }
+        };
+        let (low, high) = (self.range.0 as f64, self.range.1 as f64);
+        if high > low { (value - low) / (high - low) * 2.0 - 1.0 } else { 0.0 }
+    }
+}
+
+impl Generator2D for RasterSource {}
+
+/// Interpolates between `p[1]` and `p[2]`, with `p[0]` and `p[3]` shaping the tangents.
+fn catmull_rom(p: [f64; 4], t: f64) -> f64 {
+    let a = -0.5 * p[0] + 1.5 * p[1] - 1.5 * p[2] + 0.5 * p[3];
+    let b = p[0] - 2.5 * p[1] + 2.0 * p[2] - 0.5 * p[3];
+    let c = -0.5 * p[0] + 0.5 * p[2];
+    ((a * t + b) * t + c) * t + p[1]
+}
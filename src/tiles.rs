@@ -0,0 +1,193 @@
+use macroquad::prelude::*;
+use libnoise::prelude::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::erosion::ErosionStage;
+use crate::grid::HeightGrid;
+use crate::heightmap::{lod_divisions, terrain_height, valid_divisions, MeshParams};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Layer {
+    /// Generator samples at the vertices of a LOD.
+    Sampled(usize),
+    /// The most detailed samples of a chunk and a halo around it, after the erosion stages.
+    Eroded,
+    /// Eroded heights cross-faded with the neighbours' near the borders, at the vertices of a LOD.
+    Blended(usize),
+}
+
+struct Entry {
+    tile: Arc<OnceLock<Arc<HeightGrid>>>,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Tiles {
+    entries: HashMap<(IVec2, Layer), Entry>,
+    clock: u64,
+}
+
+/// Heights in the `[0, 1]` range of `terrain_height` for whole chunk areas, computed once and
+/// shared by neighbouring chunks, LODs and threads. A tile holds the heights at the vertices of
+/// a LOD including both borders, so adjacent tiles repeat their shared edge.
+///
+/// With erosion every chunk area is eroded once together with a halo reaching into its
+/// neighbours. Near the borders the results of adjacent areas are cross-faded, which makes both
+/// sides agree exactly without leaving an uneroded seam.
+pub struct TileCache<T: Generator<2>> {
+    generator: Arc<T>,
+    params: MeshParams,
+    /// Tiles kept before the least recently used ones are dropped.
+    capacity: usize,
+    tiles: Mutex<Tiles>,
+}
+
+impl<T: Generator<2>> TileCache<T> {
+    /// Panics if the divisions of `params` do not pass `valid_divisions`.
+    pub fn new(generator: Arc<T>, params: MeshParams, capacity: usize) -> TileCache<T> {
+        let (x_divisions, y_divisions) = params.divisions;
+        assert!(valid_divisions(x_divisions) && valid_divisions(y_divisions), "chunk divisions {:?} are not 2^k + 1", params.divisions);
+        TileCache { generator, params, capacity, tiles: Mutex::new(Tiles::default()) }
+    }
+
+    pub fn params(&self) -> &MeshParams {
+        &self.params
+    }
+
+    /// Final heights of chunk `key` at the vertices of `lod`, indexed by vertex, built on first use.
+    pub fn heights(&self, key: IVec2, lod: usize) -> Arc<HeightGrid> {
+        self.tile(key, self.heights_layer(lod))
+    }
+
+    /// Like `heights`, but returns `None` instead of building a missing tile.
+    pub fn cached_heights(&self, key: IVec2, lod: usize) -> Option<Arc<HeightGrid>> {
+        let tiles = self.tiles.lock().unwrap();
+        tiles.entries.get(&(key, self.heights_layer(lod))).and_then(|entry| entry.tile.get().cloned())
+    }
+
+    /// Height in the `[0, 1]` range at world position `(x, z)`, matching the most detailed chunk
+    /// meshes at their vertices. Without erosion the generator is sampled directly, with erosion
+    /// the tile below is interpolated and built if needed.
+    pub fn height(&self, x: f32, z: f32) -> f32 {
+        let params = &self.params;
+        if params.erosion.is_empty() {
+            return terrain_height(&*self.generator, x, z, params.terrain_scale);
+        }
+        let key = IVec2::new((x / params.chunk_size).floor() as i32, (z / params.chunk_size).floor() as i32);
+        let tile = self.heights(key, 0);
+        let (u, v) = (x / params.chunk_size - key.x as f32, z / params.chunk_size - key.y as f32);
+        tile.sample(u * (tile.width - 1) as f32, v * (tile.height - 1) as f32)
+    }
+
+    fn heights_layer(&self, lod: usize) -> Layer {
+        if self.params.erosion.is_empty() { Layer::Sampled(lod) } else { Layer::Blended(lod) }
+    }
+
+    /// Grid cells per chunk side at the most detailed LOD.
+    fn cells(&self) -> (usize, usize) {
+        (self.params.divisions.0 - 1, self.params.divisions.1 - 1)
+    }
+
+    /// Grid cells simulated around every chunk, at most one chunk.
+    fn halo(&self) -> usize {
+        let (cells, erosion) = (self.cells(), &self.params.erosion);
+        erosion.iter().map(ErosionStage::halo).max().unwrap_or(0).min(cells.0.min(cells.1))
+    }
+
+    fn tile(&self, key: IVec2, layer: Layer) -> Arc<HeightGrid> {
+        let tile = {
+            let mut tiles = self.tiles.lock().unwrap();
+            tiles.clock += 1;
+            let clock = tiles.clock;
+            let entry = tiles.entries.entry((key, layer)).or_insert_with(|| Entry { tile: Arc::default(), last_used: 0 });
+            entry.last_used = clock;
+            let tile = entry.tile.clone();
+            if tiles.entries.len() > self.capacity {
+                let oldest = tiles.entries.iter().min_by_key(|(_, entry)| entry.last_used).map(|(key, _)| *key);
+                if let Some(oldest) = oldest {
+                    tiles.entries.remove(&oldest);
+                }
+            }
+            tile
+        };
+        // built outside the lock, other threads asking for the same tile wait for this one
+        tile.get_or_init(|| Arc::new(self.build(key, layer))).clone()
+    }
+
+    fn build(&self, key: IVec2, layer: Layer) -> HeightGrid {
+        let params = &self.params;
+        let (cells, halo) = (self.cells(), self.halo());
+        match layer {
+            Layer::Sampled(lod) => {
+                let (x_divisions, y_divisions) = lod_divisions(params.divisions, lod);
+                let offset = key.as_vec2() * params.chunk_size;
+                HeightGrid::from_fn(x_divisions, y_divisions, |xi, yi| {
+                    // the same arithmetic as for the vertex positions in ChunkMesh::new
+                    let (u, v) = (xi as f32 / (x_divisions-1) as f32, yi as f32 / (y_divisions-1) as f32);
+                    terrain_height(&*self.generator, u*params.chunk_size+offset.x, v*params.chunk_size+offset.y, params.terrain_scale)
+                })
+            }
+            Layer::Eroded => {
+                let sampled = self.neighbours(key, Layer::Sampled(0));
+                // splits a grid index relative to the chunk corner into neighbour and index in it
+                let locate = |i: usize, cells: usize| match i.checked_sub(halo) {
+                    None => (0, i + cells - halo),
+                    Some(i) if i > cells => (2, i - cells),
+                    Some(i) => (1, i),
+                };
+                let mut grid = HeightGrid::from_fn(cells.0 + 2 * halo + 1, cells.1 + 2 * halo + 1, |xi, yi| {
+                    let ((tx, x), (ty, y)) = (locate(xi, cells.0), locate(yi, cells.1));
+                    sampled[ty][tx].get(x, y)
+                });
+                let origin = key * IVec2::new(cells.0 as i32, cells.1 as i32) - IVec2::splat(halo as i32);
+                let cell_size = params.chunk_size / cells.0 as f32 / params.heights.scale;
+                for stage in &params.erosion {
+                    stage.apply(&mut grid, origin, params.seed, cell_size);
+                }
+                grid
+            }
+            Layer::Blended(0) => {
+                let eroded = self.neighbours(key, Layer::Eroded);
+                let fade = (halo / 2).max(1) as f32;
+                // shares of the lower neighbour, the chunk itself and the upper neighbour
+                let weights = |i: usize, cells: usize| {
+                    let low = 0.5 * (1.0 - smoothstep(i as f32 / fade));
+                    let high = 0.5 * (1.0 - smoothstep((cells - i) as f32 / fade));
+                    [low, 1.0 - low - high, high]
+                };
+                HeightGrid::from_fn(cells.0 + 1, cells.1 + 1, |xi, yi| {
+                    let (x_weights, y_weights) = (weights(xi, cells.0), weights(yi, cells.1));
+                    // summed in world order, so both sides of a border add up the same terms the same way
+                    let mut height = 0.0;
+                    for (ty, y_weight) in y_weights.iter().enumerate() {
+                        for (tx, x_weight) in x_weights.iter().enumerate() {
+                            let weight = x_weight * y_weight;
+                            if weight != 0.0 {
+                                let (x, y) = (xi + halo + (2 - tx) * cells.0 - cells.0, yi + halo + (2 - ty) * cells.1 - cells.1);
+                                height += weight * eroded[ty][tx].get(x, y);
+                            }
+                        }
+                    }
+                    height
+                })
+            }
+            Layer::Blended(lod) => {
+                let detailed = self.tile(key, Layer::Blended(0));
+                let (x_divisions, y_divisions) = lod_divisions(params.divisions, lod);
+                let (x_step, y_step) = (cells.0 as f32 / (x_divisions-1) as f32, cells.1 as f32 / (y_divisions-1) as f32);
+                HeightGrid::from_fn(x_divisions, y_divisions, |xi, yi| detailed.sample(xi as f32 * x_step, yi as f32 * y_step))
+            }
+        }
+    }
+
+    /// The tiles of `key` and its eight neighbours, indexed `[y + 1][x + 1]` by the offset.
+    fn neighbours(&self, key: IVec2, layer: Layer) -> [[Arc<HeightGrid>; 3]; 3] {
+        [-1, 0, 1].map(|y| [-1, 0, 1].map(|x| self.tile(key + IVec2::new(x, y), layer)))
+    }
+}
+
+fn smoothstep(t: f32) -> f32 {
+    let t = t.clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
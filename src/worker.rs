@@ -0,0 +1,129 @@
+use macroquad::prelude::*;
+use libnoise::prelude::*;
+use std::collections::HashSet;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::heightmap::{ChunkMesh, MeshParams};
+use crate::tiles::TileCache;
+
+/// A chunk that should be built, ordered by `priority` (lower is built first).
+#[derive(Clone, Copy, Debug)]
+pub struct ChunkJob {
+    pub key: IVec2,
+    pub lod: usize,
+    pub priority: f32,
+}
+
+#[derive(Default)]
+struct Queue {
+    jobs: Vec<ChunkJob>,
+    in_flight: HashSet<IVec2>,
+    shutdown: bool,
+}
+
+impl Queue {
+    fn pop(&mut self) -> Option<ChunkJob> {
+        let (index, _) = self.jobs.iter().enumerate()
+            .min_by(|(_, a), (_, b)| a.priority.total_cmp(&b.priority))?;
+        let job = self.jobs.swap_remove(index);
+        self.in_flight.insert(job.key);
+        Some(job)
+    }
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    wake: Condvar,
+}
+
+/// Builds `ChunkMesh`es on background threads. The main thread replaces the wanted set of
+/// chunks every frame with `schedule` and picks up finished meshes with `poll`. The threads
+/// share one `TileCache`.
+pub struct ChunkWorkers<T: Generator<2> + Send + Sync + 'static> {
+    shared: Arc<Shared>,
+    tiles: Arc<TileCache<T>>,
+    sender: Sender<(ChunkJob, ChunkMesh)>,
+    results: Receiver<(ChunkJob, ChunkMesh)>,
+}
+
+impl<T: Generator<2> + Send + Sync + 'static> ChunkWorkers<T> {
+    pub fn new(generator: Arc<T>, params: MeshParams, cached_tiles: usize) -> ChunkWorkers<T> {
+        let shared = Arc::new(Shared { queue: Mutex::new(Queue::default()), wake: Condvar::new() });
+        let (sender, results) = channel();
+        let tiles = Arc::new(TileCache::new(generator, params, cached_tiles));
+        let workers = ChunkWorkers { shared, tiles, sender, results };
+        #[cfg(not(target_family = "wasm"))]
+        {
+            let count = std::thread::available_parallelism().map(|n| n.get().saturating_sub(1).max(1)).unwrap_or(1);
+            for _ in 0..count {
+                let shared = workers.shared.clone();
+                let tiles = workers.tiles.clone();
+                let sender = workers.sender.clone();
+                std::thread::spawn(move || work(&shared, &tiles, &sender));
+            }
+        }
+        workers
+    }
+
+    /// Replaces all queued jobs. Chunks that are no longer requested are cancelled unless a worker
+    /// has already started on them; jobs for chunks that are in flight are ignored.
+    pub fn schedule(&self, jobs: Vec<ChunkJob>) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.jobs = jobs;
+        let Queue { jobs, in_flight, .. } = &mut *queue;
+        jobs.retain(|job| !in_flight.contains(&job.key));
+        self.shared.wake.notify_all();
+    }
+
+    /// Returns at most `budget` finished meshes.
+    pub fn poll(&self, budget: usize) -> Vec<(ChunkJob, ChunkMesh)> {
+        #[cfg(target_family = "wasm")]
+        for _ in 0..budget {
+            // no threads on the web, build on the main thread instead
+            let Some(job) = self.shared.queue.lock().unwrap().pop() else { break };
+            let mesh = ChunkMesh::new(&*self.tiles, job.key, job.lod);
+            self.sender.send((job, mesh)).unwrap();
+        }
+        let finished: Vec<_> = self.results.try_iter().take(budget).collect();
+        let mut queue = self.shared.queue.lock().unwrap();
+        for (job, _) in &finished {
+            queue.in_flight.remove(&job.key);
+        }
+        finished
+    }
+
+    pub fn tiles(&self) -> &TileCache<T> {
+        &self.tiles
+    }
+
+    pub fn in_flight(&self) -> usize {
+        let queue = self.shared.queue.lock().unwrap();
+        queue.jobs.len() + queue.in_flight.len()
+    }
+}
+
+/// Tells the threads to stop without waiting for them, so replacing the workers does not stall
+/// the frame. Threads busy with a mesh finish it and drop it.
+impl<T: Generator<2> + Send + Sync + 'static> Drop for ChunkWorkers<T> {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().shutdown = true;
+        self.shared.wake.notify_all();
+    }
+}
+
+#[cfg_attr(target_family = "wasm", allow(dead_code))]
+fn work<T: Generator<2>>(shared: &Shared, tiles: &TileCache<T>, sender: &Sender<(ChunkJob, ChunkMesh)>) {
+    loop {
+        let job = {
+            let mut queue = shared.queue.lock().unwrap();
+            loop {
+                if queue.shutdown { return; }
+                if let Some(job) = queue.pop() { break job; }
+                queue = shared.wake.wait(queue).unwrap();
+            }
+        };
+        let mesh = ChunkMesh::new(tiles, job.key, job.lod);
+        if sender.send((job, mesh)).is_err() { return; }
+    }
+}